@@ -0,0 +1 @@
+ALTER TABLE uploads ADD COLUMN placeholder TEXT DEFAULT NULL; -- BlurHash of the accepted image
@@ -0,0 +1,2 @@
+ALTER TABLE uploads ADD COLUMN dominant_color TEXT   DEFAULT NULL; -- e.g. '#1e90ff'
+ALTER TABLE uploads ADD COLUMN palette        TEXT[] DEFAULT NULL; -- most common color first
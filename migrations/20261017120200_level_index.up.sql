@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS uploads_accepted_level_id_idx ON uploads (level_id) WHERE accepted = TRUE;
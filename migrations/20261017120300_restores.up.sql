@@ -0,0 +1,9 @@
+ALTER TABLE uploads ADD COLUMN restored_time TIMESTAMP DEFAULT NULL; -- set when an older version is made current again
+ALTER TABLE uploads ADD COLUMN restored_by   BIGINT    DEFAULT NULL REFERENCES users (id) ON DELETE SET NULL;
+
+-- The current thumbnail of every level: its latest accepted upload, unless an older one was restored since
+CREATE VIEW current_uploads AS
+SELECT DISTINCT ON (level_id) level_id, id
+FROM uploads
+WHERE accepted = TRUE
+ORDER BY level_id, GREATEST(upload_time, restored_time) DESC, id DESC;
@@ -0,0 +1,9 @@
+ALTER TABLE uploads ADD COLUMN deleted_time  TIMESTAMP DEFAULT NULL; -- deleted uploads are kept for auditing
+ALTER TABLE uploads ADD COLUMN deleted_by    BIGINT    DEFAULT NULL REFERENCES users (id) ON DELETE SET NULL;
+ALTER TABLE uploads ADD COLUMN delete_reason TEXT      DEFAULT NULL;
+
+CREATE OR REPLACE VIEW current_uploads AS
+SELECT DISTINCT ON (level_id) level_id, id
+FROM uploads
+WHERE accepted = TRUE AND deleted_time IS NULL
+ORDER BY level_id, GREATEST(upload_time, restored_time) DESC, id DESC;
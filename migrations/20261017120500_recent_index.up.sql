@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS uploads_accepted_time_idx ON uploads (accepted_time DESC, id DESC) WHERE accepted = TRUE;
@@ -0,0 +1,2 @@
+ALTER TABLE uploads ADD COLUMN focal_x REAL DEFAULT NULL; -- 0 (left) to 1 (right)
+ALTER TABLE uploads ADD COLUMN focal_y REAL DEFAULT NULL; -- 0 (top) to 1 (bottom)
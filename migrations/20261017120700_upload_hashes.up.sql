@@ -0,0 +1 @@
+ALTER TABLE uploads ADD COLUMN phash BIGINT DEFAULT NULL; -- 64-bit dHash of the normalized image
@@ -0,0 +1,2 @@
+ALTER TABLE uploads ADD COLUMN quality_flags TEXT[] NOT NULL DEFAULT '{}'; -- e.g. '{too_dark,letterboxed}'
+ALTER TABLE uploads ADD COLUMN quality_score REAL   DEFAULT NULL;         -- 0 (broken) to 1 (fine)
@@ -0,0 +1,23 @@
+-- Every time an older version was made current again, uploads.restored_time/restored_by only keep the latest
+CREATE TABLE IF NOT EXISTS thumbnail_reverts
+(
+    id            BIGSERIAL PRIMARY KEY,
+    level_id      BIGINT                 NOT NULL,
+    upload_id     BIGINT                 NOT NULL REFERENCES uploads (id) ON DELETE CASCADE,
+    reverted_time TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    reverted_by   BIGINT    DEFAULT NULL REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS thumbnail_reverts_level_id_idx ON thumbnail_reverts (level_id);
+
+INSERT INTO thumbnail_reverts (level_id, upload_id, reverted_time, reverted_by)
+SELECT level_id, id, restored_time, restored_by
+FROM uploads
+WHERE restored_time IS NOT NULL;
+
+-- Uploads become the current thumbnail when they are accepted, not when they are submitted
+CREATE OR REPLACE VIEW current_uploads AS
+SELECT DISTINCT ON (level_id) level_id, id
+FROM uploads
+WHERE accepted = TRUE AND deleted_time IS NULL
+ORDER BY level_id, GREATEST(accepted_time, restored_time) DESC, id DESC;
@@ -0,0 +1,2 @@
+-- Reverts made by the server itself, like falling back to the previous version of a deleted thumbnail
+ALTER TABLE thumbnail_reverts ADD COLUMN automatic BOOLEAN NOT NULL DEFAULT FALSE;
@@ -25,7 +25,7 @@ impl UserSession {
 
     pub fn from_jwt(token: &str) -> Result<Self, jsonwebtoken::errors::Error> {
         // strip the "Bearer " prefix if it exists
-        let token = if token.starts_with("Bearer ") { &token[7..] } else { token };
+        let token = token.strip_prefix("Bearer ").unwrap_or(token);
 
         let mut validation = jsonwebtoken::Validation::default();
         validation.validate_exp = false;
@@ -0,0 +1,102 @@
+// Bloom filter of level ids, downloaded by clients to skip requests for missing thumbnails.
+//
+// Clients check an id the same way it was inserted:
+//   h = FNV-1a 64 of the id as 8 little-endian bytes
+//   for i in 0..hashes: bit = (low 32 bits of h + i * high 32 bits of h) mod bits
+// Bit n is stored in byte n / 8, under the mask 1 << (n % 8).
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// ~9.6 bits per id with 7 hashes gives about 1% false positives
+const BITS_PER_ITEM: usize = 10;
+const HASH_COUNT: u32 = 7;
+
+pub struct BloomFilter {
+    bits: Vec<u8>,
+}
+
+fn fnv1a(id: i64) -> u64 {
+    id.to_le_bytes()
+        .iter()
+        .fold(FNV_OFFSET, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+impl BloomFilter {
+    pub fn with_capacity(items: usize) -> Self {
+        Self {
+            bits: vec![0; (items.max(1) * BITS_PER_ITEM).div_ceil(8)],
+        }
+    }
+
+    pub fn bit_count(&self) -> u64 {
+        self.bits.len() as u64 * 8
+    }
+
+    pub fn hash_count(&self) -> u32 {
+        HASH_COUNT
+    }
+
+    pub fn insert(&mut self, id: i64) {
+        let hash = fnv1a(id);
+        let (low, high) = (hash & 0xffffffff, hash >> 32);
+        for i in 0..HASH_COUNT as u64 {
+            let bit = (low + i * high) % self.bit_count();
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Looks an id up in the serialized filter the way clients do, following the format above
+    fn client_contains(bytes: &[u8], id: i64) -> bool {
+        let bits = bytes.len() as u64 * 8;
+        let hash = fnv1a(id);
+        let (low, high) = (hash & 0xffffffff, hash >> 32);
+        (0..HASH_COUNT as u64).all(|i| {
+            let bit = (low + i * high) % bits;
+            bytes[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    #[test]
+    fn fnv1a_of_little_endian_id() {
+        assert_eq!(fnv1a(1), 0x89cd31291d2aefa4);
+    }
+
+    #[test]
+    fn serialized_format() {
+        let mut filter = BloomFilter::with_capacity(1);
+        filter.insert(1);
+        assert_eq!(filter.bit_count(), 16);
+        assert_eq!(filter.into_bytes(), vec![82, 165]);
+
+        let mut filter = BloomFilter::with_capacity(4);
+        for id in [1, 2, 3] {
+            filter.insert(id);
+        }
+        assert_eq!(filter.into_bytes(), vec![216, 146, 164, 141, 201]);
+    }
+
+    #[test]
+    fn false_positive_rate() {
+        let mut filter = BloomFilter::with_capacity(10_000);
+        for id in 0..10_000 {
+            filter.insert(id * 3);
+        }
+        let bytes = filter.into_bytes();
+
+        assert!((0..10_000).all(|id| client_contains(&bytes, id * 3)));
+
+        let false_positives =
+            (0..100_000).filter(|id| client_contains(&bytes, 1_000_000 + id)).count();
+        assert!(false_positives < 2_000, "{} false positives in 100000", false_positives);
+    }
+}
@@ -0,0 +1,115 @@
+use image::RgbImage;
+use std::f32::consts::PI;
+
+// BlurHash encoder, see https://github.com/woltapp/blurhash/blob/master/Algorithm.md
+
+const BASE83_CHARS: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+// 4x3 components fit the 16:9 thumbnails well and give a 28 character hash
+const X_COMPONENTS: u32 = 4;
+const Y_COMPONENTS: u32 = 3;
+
+// The hash only keeps low frequencies, so a tiny copy of the image is plenty
+const SAMPLE_WIDTH: u32 = 64;
+const SAMPLE_HEIGHT: u32 = 36;
+
+fn encode_base83(value: u32, length: u32, output: &mut String) {
+    for i in 1..=length {
+        let digit = (value / 83u32.pow(length - i)) % 83;
+        output.push(BASE83_CHARS[digit as usize] as char);
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let v = value as f32 / 255.0;
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(value: f32) -> u32 {
+    let v = value.clamp(0.0, 1.0);
+    if v <= 0.0031308 {
+        (v * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * v.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn sign_pow(value: f32, exp: f32) -> f32 {
+    value.abs().powf(exp).copysign(value)
+}
+
+pub fn encode(image: &RgbImage) -> String {
+    let sample = image::imageops::thumbnail(image, SAMPLE_WIDTH, SAMPLE_HEIGHT);
+    let (width, height) = sample.dimensions();
+
+    let mut factors = Vec::with_capacity((X_COMPONENTS * Y_COMPONENTS) as usize);
+    for j in 0..Y_COMPONENTS {
+        for i in 0..X_COMPONENTS {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0f32; 3];
+
+            for (x, y, pixel) in sample.enumerate_pixels() {
+                let basis = normalisation
+                    * (PI * i as f32 * x as f32 / width as f32).cos()
+                    * (PI * j as f32 * y as f32 / height as f32).cos();
+                for (channel, value) in factor.iter_mut().zip(pixel.0) {
+                    *channel += basis * srgb_to_linear(value);
+                }
+            }
+
+            let scale = 1.0 / (width * height) as f32;
+            factors.push(factor.map(|channel| channel * scale));
+        }
+    }
+
+    let mut hash = String::new();
+    encode_base83((X_COMPONENTS - 1) + (Y_COMPONENTS - 1) * 9, 1, &mut hash);
+
+    let (dc, ac) = factors.split_first().unwrap();
+    let actual_max = ac.iter().flatten().fold(0.0f32, |max, value| max.max(value.abs()));
+    let quantised_max = ((actual_max * 166.0 - 0.5).floor() as i32).clamp(0, 82) as u32;
+    let maximum_value = (quantised_max + 1) as f32 / 166.0;
+    encode_base83(quantised_max, 1, &mut hash);
+
+    let dc_value =
+        (linear_to_srgb(dc[0]) << 16) + (linear_to_srgb(dc[1]) << 8) + linear_to_srgb(dc[2]);
+    encode_base83(dc_value, 4, &mut hash);
+
+    for factor in ac {
+        let [r, g, b] = factor.map(|channel| {
+            ((sign_pow(channel / maximum_value, 0.5) * 9.0 + 9.5).floor() as i32).clamp(0, 18)
+                as u32
+        });
+        encode_base83(r * 19 * 19 + g * 19 + b, 2, &mut hash);
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expected hashes come from an independent implementation of the algorithm description
+    #[test]
+    fn known_vectors() {
+        let black = RgbImage::from_pixel(160, 90, image::Rgb([0, 0, 0]));
+        assert_eq!(encode(&black), "L00000fQfQfQfQfQfQfQfQfQfQfQ");
+
+        let white = RgbImage::from_pixel(160, 90, image::Rgb([255, 255, 255]));
+        assert_eq!(encode(&white), "L8TSUA?bfQ?b~qj[fQj[fQfQfQfQ");
+
+        let gradient =
+            RgbImage::from_fn(64, 36, |x, y| image::Rgb([x as u8 * 4, y as u8 * 7, 128]));
+        assert_eq!(encode(&gradient), "L#HLYY2Y$5Sgl}WWjtf7gJfjfQfj");
+    }
+
+    #[test]
+    fn base83() {
+        let mut output = String::new();
+        encode_base83(21, 1, &mut output);
+        encode_base83(3429, 2, &mut output);
+        assert_eq!(output, "LfQ");
+    }
+}
@@ -1,5 +1,13 @@
 use tracing::{error, info, warn};
 
+// Cloudflare accepts at most 30 URLs per purge request
+const MAX_PURGE_FILES: usize = 30;
+
+// Sent as the Cache-Tag header on every thumbnail image, so all of them can be purged at once
+pub fn level_tag(level_id: i64) -> String {
+    format!("level-{}", level_id)
+}
+
 struct CloudflareClient {
     api_token: String,
     zone_id: String,
@@ -44,19 +52,33 @@ impl CloudflareClient {
         }
     }
 
-    pub async fn purge_thumbnail(&self, level_id: i64) -> Result<(), PurgeError> {
-        let urls = [
+    pub async fn purge_thumbnail(
+        &self,
+        level_id: i64,
+        extra_paths: &[String],
+    ) -> Result<(), PurgeError> {
+        let mut urls = vec![
             format!("{}/thumbnail/{}", self.root_url, level_id),
             format!("{}/thumbnail/{}/small", self.root_url, level_id),
             format!("{}/thumbnail/{}/medium", self.root_url, level_id),
             format!("{}/thumbnail/{}/high", self.root_url, level_id),
             format!("{}/thumbnail/{}/info", self.root_url, level_id),
+            format!("{}/thumbnail/{}/blur", self.root_url, level_id),
         ];
+        urls.extend(extra_paths.iter().map(|path| format!("{}{}", self.root_url, path)));
+
+        for chunk in urls.chunks(MAX_PURGE_FILES) {
+            self.send_purge(serde_json::json!({ "files": chunk })).await?;
+        }
 
+        // Catches every other variant (e.g. custom sizes), whether or not it is cached locally
+        self.send_purge(serde_json::json!({ "tags": [level_tag(level_id)] })).await
+    }
+
+    async fn send_purge(&self, payload: serde_json::Value) -> Result<(), PurgeError> {
         let endpoint =
             format!("https://api.cloudflare.com/client/v4/zones/{}/purge_cache", self.zone_id);
 
-        let payload = serde_json::json!({ "files": urls });
         let response =
             self.client.post(&endpoint).bearer_auth(&self.api_token).json(&payload).send().await;
 
@@ -78,13 +100,10 @@ impl CloudflareClient {
             Err(PurgeError { status, body: text })
         }
     }
-
-    pub async fn get_user_stats(&self) -> Result<u64, String> {
-        Ok(0)
-    }
 }
 
-pub fn purge(level_id: i64) {
+// Purges the fixed thumbnail URLs of a level, any extra paths and everything tagged with it
+pub fn purge(level_id: i64, extra_paths: Vec<String>) {
     if dotenv::var("CLOUDFLARE_API_KEY").is_err() {
         warn!("CLOUDFLARE_API_KEY is not set, not purging level {}", level_id);
         return;
@@ -94,7 +113,7 @@ pub fn purge(level_id: i64) {
         let max_retries = 5;
 
         for attempt in 1..=max_retries {
-            match CloudflareClient::get().purge_thumbnail(level_id).await {
+            match CloudflareClient::get().purge_thumbnail(level_id, &extra_paths).await {
                 Ok(_) => {
                     if attempt > 1 {
                         info!("Purge for id {} succeeded after {} attempt(s)", level_id, attempt);
@@ -1,7 +1,10 @@
 use sqlx::postgres::PgPoolOptions;
 use sqlx::{FromRow, Postgres, QueryBuilder};
-use std::path::Path;
 
+use crate::dhash;
+use crate::encoding::EncodingSettings;
+use crate::hash_index::{HashEntry, HashIndex};
+use crate::thumbnail_index::ThumbnailIndex;
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
@@ -9,12 +12,16 @@ use std::sync::Arc;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Settings {
     pub pause_submissions: bool,
+    #[serde(default)]
+    pub encoding: EncodingSettings,
 }
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub pool: Arc<sqlx::Pool<Postgres>>,
     pub settings: Arc<tokio::sync::RwLock<Settings>>,
+    pub index: Arc<ThumbnailIndex>,
+    pub hashes: Arc<HashIndex>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
@@ -49,13 +56,24 @@ pub struct User {
 
 #[derive(FromRow)]
 pub struct UploadInfo {
+    pub id: i64,
     pub account_id: i64,
     pub username: String,
+    pub modified_time: Option<NaiveDateTime>, // when it was accepted or last restored
+    pub focal_x: Option<f32>,
+    pub focal_y: Option<f32>,
+}
+
+impl UploadInfo {
+    pub fn focal_point(&self) -> Option<(f32, f32)> {
+        Some((self.focal_x?, self.focal_y?))
+    }
 }
 
 #[derive(FromRow, Serialize, Deserialize)]
 pub struct UploadExtended {
     pub level_id: i64,
+    pub upload_id: i64,
     pub account_id: i64,
     pub username: String,
     pub upload_time: NaiveDateTime,
@@ -63,6 +81,91 @@ pub struct UploadExtended {
     pub accepted_time: Option<NaiveDateTime>,
     pub accepted_by: Option<i64>,
     pub accepted_by_username: Option<String>,
+    pub restored_time: Option<NaiveDateTime>,
+    pub restored_by: Option<i64>,
+    pub restored_by_username: Option<String>,
+    pub placeholder: Option<String>,
+    pub dominant_color: Option<String>,
+    pub palette: Option<Vec<String>>,
+    pub focal_x: Option<f32>,
+    pub focal_y: Option<f32>,
+}
+
+// Computed once a thumbnail is accepted
+pub struct ThumbnailMetadata {
+    pub placeholder: String,
+    pub dominant_color: String,
+    pub palette: Vec<String>,
+    pub phash: i64, // only stored if the upload didn't get one when it was submitted
+}
+
+#[derive(FromRow, Serialize)]
+pub struct ThumbnailRevert {
+    pub upload_id: i64,
+    pub reverted_time: Option<NaiveDateTime>,
+    pub reverted_by: Option<i64>,
+    pub reverted_by_username: Option<String>,
+    pub automatic: bool, // whether this was a fallback after a deletion rather than a moderator
+}
+
+#[derive(FromRow, Serialize)]
+pub struct ThumbnailVersion {
+    pub id: i64,
+    pub account_id: i64,
+    pub username: String,
+    pub upload_time: NaiveDateTime,
+    pub accepted_time: Option<NaiveDateTime>,
+    pub accepted_by: Option<i64>,
+    pub accepted_by_username: Option<String>,
+    pub restored_time: Option<NaiveDateTime>,
+    pub restored_by: Option<i64>,
+    pub restored_by_username: Option<String>,
+    pub current: bool,
+    #[sqlx(skip)]
+    pub available: bool,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct RecentThumbnail {
+    pub id: i64,
+    pub level_id: i64,
+    pub account_id: i64,
+    pub username: String,
+    pub accepted_time: NaiveDateTime,
+    pub accepted_by: Option<i64>,
+    pub accepted_by_username: Option<String>,
+    pub placeholder: Option<String>,
+    pub dominant_color: Option<String>,
+    pub replacement: bool, // whether the level already had a thumbnail before this one
+}
+
+#[derive(Debug, Clone)]
+pub struct RecentQueryOptions {
+    pub before: Option<(NaiveDateTime, i64)>, // accepted time and id of the last item already seen
+    pub limit: u32,
+    pub replacement_only: bool,
+    pub new_only: bool,
+}
+
+// Computed from the image when it is uploaded
+#[derive(Debug, Clone)]
+pub struct UploadAnalysis {
+    pub phash: i64,
+    pub quality_flags: Vec<String>,
+    pub quality_score: f32,
+}
+
+// An earlier upload that looks the same as a new one
+#[derive(FromRow)]
+pub struct DuplicateUpload {
+    pub id: i64,
+    pub accepted: bool,
+}
+
+#[derive(FromRow)]
+pub struct LevelPlaceholder {
+    pub level_id: i64,
+    pub placeholder: Option<String>,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
@@ -73,9 +176,24 @@ pub struct PendingUpload {
     pub level_id: i64,
     pub accepted: bool,
     pub upload_time: NaiveDateTime,
+    pub quality_flags: Vec<String>,
+    pub quality_score: Option<f32>,
 
     #[sqlx(skip)]
     pub replacement: bool,
+    #[sqlx(skip)]
+    pub cross_level_match: bool, // looks like a thumbnail or pending upload of another level
+}
+
+// A thumbnail or pending upload of another level that looks the same as a pending upload
+#[derive(Debug, Clone, Serialize)]
+pub struct CrossLevelMatch {
+    #[serde(skip)]
+    pub pending_id: i64,
+    pub level_id: i64,
+    pub upload_id: i64,
+    pub pending: bool,
+    pub distance: i64,
 }
 
 #[derive(Debug, Clone)]
@@ -89,6 +207,14 @@ pub struct PendingQueryOptions {
     pub new_only: bool,
 }
 
+// Narrows down which current thumbnails a random pick is made from
+#[derive(Debug, Clone, Default)]
+pub struct RandomFilter {
+    pub uploader: Option<String>,
+    pub accepted_after: Option<NaiveDateTime>,
+    pub first_accepted_before: Option<NaiveDateTime>, // when the level first got a thumbnail
+}
+
 #[derive(Debug, Clone)]
 pub struct PendingUploadsPage {
     pub uploads: Vec<PendingUpload>,
@@ -108,6 +234,38 @@ pub struct UserStats {
     pub active_thumbnail_count: i64,
 }
 
+// Keeps the moderation queue readable when an image matches lots of uploads
+const MAX_CROSS_LEVEL_MATCHES: usize = 10;
+
+// Columns and joins of UploadExtended, shared by the single and batch info queries
+const UPLOAD_EXTENDED_QUERY: &str = "
+                    uploads.level_id,
+                    uploads.id AS upload_id,
+                    users.account_id,
+                    users.username,
+                    uploads.upload_time,
+                    (
+                        SELECT MIN(upload_time) FROM uploads u2
+                        WHERE u2.level_id = uploads.level_id
+                          AND u2.accepted = TRUE AND u2.deleted_time IS NULL
+                    ) AS first_upload_time,
+                    uploads.accepted_time,
+                    accepted_by.account_id AS accepted_by,
+                    accepted_by.username AS accepted_by_username,
+                    uploads.restored_time,
+                    restored_by.account_id AS restored_by,
+                    restored_by.username AS restored_by_username,
+                    uploads.placeholder,
+                    uploads.dominant_color,
+                    uploads.palette,
+                    uploads.focal_x,
+                    uploads.focal_y
+                 FROM current_uploads
+                 JOIN uploads ON uploads.id = current_uploads.id
+                 JOIN users ON uploads.user_id = users.id
+                 LEFT JOIN users AS accepted_by ON uploads.accepted_by = accepted_by.id
+                 LEFT JOIN users AS restored_by ON uploads.restored_by = restored_by.id";
+
 impl AppState {
     pub async fn new() -> Self {
         let connection_string = dotenv::var("DATABASE_URL").expect("DATABASE_URL must be set");
@@ -121,25 +279,39 @@ impl AppState {
         sqlx::migrate!("./migrations").run(&pool).await.expect("Failed to run migrations");
 
         // load settings from state.json or create default
+        let default_settings = Settings {
+            pause_submissions: false,
+            encoding: EncodingSettings::default(),
+        };
         let settings = if let Ok(settings_data) = tokio::fs::read_to_string("state.json").await {
-            serde_json::from_str(&settings_data).unwrap_or(Settings { pause_submissions: false })
+            serde_json::from_str(&settings_data).unwrap_or(default_settings)
         } else {
-            Settings { pause_submissions: false }
+            default_settings
         };
 
+        let index = ThumbnailIndex::build("thumbnails").await.expect("Failed to index thumbnails");
+
         AppState {
             pool: Arc::new(pool),
             settings: Arc::new(tokio::sync::RwLock::new(settings)),
+            index: Arc::new(index),
+            hashes: Arc::new(HashIndex::default()),
         }
     }
 
     pub async fn get_upload_info(&self, id: i64) -> Option<UploadInfo> {
         sqlx::query_as::<_, UploadInfo>(
-            "SELECT users.account_id, users.username
-                 FROM uploads
+            "SELECT
+                    uploads.id,
+                    users.account_id,
+                    users.username,
+                    GREATEST(uploads.accepted_time, uploads.restored_time) AS modified_time,
+                    uploads.focal_x,
+                    uploads.focal_y
+                 FROM current_uploads
+                 JOIN uploads ON uploads.id = current_uploads.id
                  JOIN users ON uploads.user_id = users.id
-                 WHERE uploads.level_id = $1 AND accepted = TRUE
-                 ORDER BY upload_time DESC LIMIT 1",
+                 WHERE current_uploads.level_id = $1",
         )
         .bind(id)
         .fetch_optional(&*self.pool)
@@ -148,31 +320,295 @@ impl AppState {
     }
 
     pub async fn get_upload_extended(&self, id: i64) -> Option<UploadExtended> {
-        sqlx::query_as::<_, UploadExtended>(
-            "SELECT 
-                    uploads.level_id,
+        let query = format!("SELECT {} WHERE current_uploads.level_id = $1", UPLOAD_EXTENDED_QUERY);
+
+        sqlx::query_as::<_, UploadExtended>(&query)
+            .bind(id)
+            .fetch_optional(&*self.pool)
+            .await
+            .ok()?
+    }
+
+    // Current thumbnail info for each of the given levels that has one
+    pub async fn get_upload_extended_batch(
+        &self,
+        level_ids: &[i64],
+    ) -> Result<Vec<UploadExtended>, sqlx::Error> {
+        let query =
+            format!("SELECT {} WHERE current_uploads.level_id = ANY($1)", UPLOAD_EXTENDED_QUERY);
+
+        sqlx::query_as::<_, UploadExtended>(&query).bind(level_ids).fetch_all(&*self.pool).await
+    }
+
+    // Current thumbnails, most recently accepted first, optionally only those of one user
+    pub async fn get_feed_uploads(
+        &self,
+        user_id: Option<i64>,
+        limit: u32,
+    ) -> Result<Vec<UploadExtended>, sqlx::Error> {
+        let query = format!(
+            "SELECT {}
+                 WHERE uploads.accepted_time IS NOT NULL AND ($1::BIGINT IS NULL OR users.id = $1)
+                 ORDER BY uploads.accepted_time DESC, uploads.id DESC
+                 LIMIT $2",
+            UPLOAD_EXTENDED_QUERY
+        );
+
+        sqlx::query_as::<_, UploadExtended>(&query)
+            .bind(user_id)
+            .bind(limit as i64)
+            .fetch_all(&*self.pool)
+            .await
+    }
+
+    // Every accepted upload of a level, newest first
+    pub async fn get_thumbnail_history(
+        &self,
+        level_id: i64,
+    ) -> Result<Vec<ThumbnailVersion>, sqlx::Error> {
+        sqlx::query_as::<_, ThumbnailVersion>(
+            "SELECT
+                    uploads.id,
                     users.account_id,
                     users.username,
                     uploads.upload_time,
-                    (
-                        SELECT MIN(upload_time) FROM uploads u2
-                        WHERE u2.level_id = uploads.level_id AND u2.accepted = TRUE
-                    ) AS first_upload_time,
                     uploads.accepted_time,
                     accepted_by.account_id AS accepted_by,
-                    accepted_by.username AS accepted_by_username
+                    accepted_by.username AS accepted_by_username,
+                    uploads.restored_time,
+                    restored_by.account_id AS restored_by,
+                    restored_by.username AS restored_by_username,
+                    current_uploads.id IS NOT NULL AS current
                  FROM uploads
                  JOIN users ON uploads.user_id = users.id
                  LEFT JOIN users AS accepted_by ON uploads.accepted_by = accepted_by.id
-                 WHERE uploads.level_id = $1 AND accepted = TRUE
-                 ORDER BY upload_time DESC LIMIT 1",
+                 LEFT JOIN users AS restored_by ON uploads.restored_by = restored_by.id
+                 LEFT JOIN current_uploads ON current_uploads.id = uploads.id
+                 WHERE uploads.level_id = $1 AND accepted = TRUE AND deleted_time IS NULL
+                 ORDER BY accepted_time DESC, uploads.id DESC",
         )
-        .bind(id)
+        .bind(level_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn get_upload_version(&self, level_id: i64, upload_id: i64) -> Option<UploadInfo> {
+        sqlx::query_as::<_, UploadInfo>(
+            "SELECT
+                    uploads.id,
+                    users.account_id,
+                    users.username,
+                    uploads.accepted_time AS modified_time,
+                    uploads.focal_x,
+                    uploads.focal_y
+                 FROM uploads
+                 JOIN users ON uploads.user_id = users.id
+                 WHERE uploads.level_id = $1 AND uploads.id = $2
+                   AND accepted = TRUE AND deleted_time IS NULL",
+        )
+        .bind(level_id)
+        .bind(upload_id)
         .fetch_optional(&*self.pool)
         .await
         .ok()?
     }
 
+    // Accepted uploads, most recently accepted first
+    pub async fn get_recent_thumbnails(
+        &self,
+        options: &RecentQueryOptions,
+    ) -> Result<Vec<RecentThumbnail>, sqlx::Error> {
+        const REPLACEMENT: &str = "EXISTS (
+                        SELECT 1 FROM uploads u2
+                        WHERE u2.level_id = uploads.level_id AND u2.accepted = TRUE
+                          AND u2.accepted_time < uploads.accepted_time
+                    )";
+
+        let mut builder = QueryBuilder::new(format!(
+            "SELECT
+                    uploads.id,
+                    uploads.level_id,
+                    users.account_id,
+                    users.username,
+                    uploads.accepted_time,
+                    accepted_by.account_id AS accepted_by,
+                    accepted_by.username AS accepted_by_username,
+                    uploads.placeholder,
+                    uploads.dominant_color,
+                    {} AS replacement
+                 FROM uploads
+                 JOIN users ON uploads.user_id = users.id
+                 LEFT JOIN users AS accepted_by ON uploads.accepted_by = accepted_by.id
+                 WHERE uploads.accepted = TRUE AND uploads.deleted_time IS NULL
+                   AND uploads.accepted_time IS NOT NULL",
+            REPLACEMENT
+        ));
+
+        if let Some((accepted_time, id)) = options.before {
+            builder
+                .push(" AND (uploads.accepted_time, uploads.id) < (")
+                .push_bind(accepted_time)
+                .push(", ")
+                .push_bind(id)
+                .push(")");
+        }
+
+        if options.replacement_only {
+            builder.push(" AND ").push(REPLACEMENT);
+        } else if options.new_only {
+            builder.push(" AND NOT ").push(REPLACEMENT);
+        }
+
+        builder
+            .push(" ORDER BY uploads.accepted_time DESC, uploads.id DESC LIMIT ")
+            .push_bind(options.limit as i64);
+
+        builder.build_query_as::<RecentThumbnail>().fetch_all(&*self.pool).await
+    }
+
+    pub async fn set_focal_point(
+        &self,
+        upload_id: i64,
+        focal_point: Option<(f32, f32)>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE uploads SET focal_x = $1, focal_y = $2 WHERE id = $3")
+            .bind(focal_point.map(|(x, _)| x))
+            .bind(focal_point.map(|(_, y)| y))
+            .bind(upload_id)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Makes an earlier accepted upload the current thumbnail of its level again. The returned
+    // transaction has to be committed once the current file was replaced.
+    pub async fn restore_upload(
+        &self,
+        upload_id: i64,
+        restored_by: i64,
+    ) -> Result<sqlx::Transaction<'static, Postgres>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE uploads SET restored_time = NOW(), restored_by = $1 WHERE id = $2")
+            .bind(restored_by)
+            .bind(upload_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO thumbnail_reverts (level_id, upload_id, reverted_time, reverted_by)
+                 SELECT level_id, id, restored_time, restored_by FROM uploads WHERE id = $1",
+        )
+        .bind(upload_id)
+        .execute(&mut *tx)
+        .await?;
+        Ok(tx)
+    }
+
+    // Every revert of a level, newest first
+    pub async fn get_thumbnail_reverts(
+        &self,
+        level_id: i64,
+    ) -> Result<Vec<ThumbnailRevert>, sqlx::Error> {
+        sqlx::query_as::<_, ThumbnailRevert>(
+            "SELECT
+                    thumbnail_reverts.upload_id,
+                    thumbnail_reverts.reverted_time,
+                    users.account_id AS reverted_by,
+                    users.username AS reverted_by_username,
+                    thumbnail_reverts.automatic
+                 FROM thumbnail_reverts
+                 LEFT JOIN users ON thumbnail_reverts.reverted_by = users.id
+                 WHERE thumbnail_reverts.level_id = $1
+                 ORDER BY thumbnail_reverts.reverted_time DESC, thumbnail_reverts.id DESC",
+        )
+        .bind(level_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Deletes the current upload of a level and, if given, makes an older version current
+    // again. The returned transaction has to be committed once the files agree with it.
+    pub async fn delete_current_upload(
+        &self,
+        upload_id: i64,
+        deleted_by: i64,
+        reason: &str,
+        fallback: Option<i64>,
+    ) -> Result<sqlx::Transaction<'static, Postgres>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "UPDATE uploads SET deleted_time = NOW(), deleted_by = $1, delete_reason = $2 WHERE id = $3",
+        )
+        .bind(deleted_by)
+        .bind(reason)
+        .bind(upload_id)
+        .execute(&mut *tx)
+        .await?;
+
+        if let Some(fallback) = fallback {
+            // restored_by stays empty, nobody chose this version
+            sqlx::query(
+                "UPDATE uploads SET restored_time = NOW(), restored_by = NULL WHERE id = $1",
+            )
+            .bind(fallback)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                "INSERT INTO thumbnail_reverts (level_id, upload_id, reverted_time, automatic)
+                     SELECT level_id, id, restored_time, TRUE FROM uploads WHERE id = $1",
+            )
+            .bind(fallback)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        Ok(tx)
+    }
+
+    // Which of the given levels have an accepted thumbnail
+    pub async fn get_existing_level_ids(&self, level_ids: &[i64]) -> Result<Vec<i64>, sqlx::Error> {
+        sqlx::query_scalar("SELECT level_id FROM current_uploads WHERE level_id = ANY($1)")
+            .bind(level_ids)
+            .fetch_all(&*self.pool)
+            .await
+    }
+
+    // Levels whose current thumbnail matches the filter, ordered by level id
+    pub async fn get_random_candidates(
+        &self,
+        filter: &RandomFilter,
+    ) -> Result<Vec<i64>, sqlx::Error> {
+        let mut builder = QueryBuilder::new(
+            "SELECT current_uploads.level_id FROM current_uploads
+                 JOIN uploads ON uploads.id = current_uploads.id
+                 JOIN users ON uploads.user_id = users.id
+                 WHERE TRUE",
+        );
+
+        if let Some(ref uploader) = filter.uploader {
+            builder
+                .push(" AND LOWER(users.username) = LOWER(")
+                .push_bind(uploader.clone())
+                .push(")");
+        }
+
+        if let Some(accepted_after) = filter.accepted_after {
+            builder.push(" AND uploads.accepted_time >= ").push_bind(accepted_after);
+        }
+
+        if let Some(first_accepted_before) = filter.first_accepted_before {
+            builder
+                .push(
+                    " AND (SELECT MIN(first.accepted_time) FROM uploads first
+                         WHERE first.level_id = current_uploads.level_id
+                         AND first.accepted = TRUE AND first.deleted_time IS NULL) < ",
+                )
+                .push_bind(first_accepted_before);
+        }
+
+        builder.push(" ORDER BY current_uploads.level_id");
+        builder.build_query_scalar().fetch_all(&*self.pool).await
+    }
+
     pub async fn find_or_create_user(
         &self,
         account_id: i64,
@@ -253,23 +689,108 @@ impl AppState {
         user_id: i64,
         image_path: &str,
         accepted: bool,
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query(if accepted {
-            "INSERT INTO uploads (level_id, user_id, image_path, accepted, accepted_time, accepted_by)
-                     VALUES ($1, $2, $3, $4, NOW(), $2)"
+        analysis: &UploadAnalysis,
+    ) -> Result<i64, sqlx::Error> {
+        let id = sqlx::query_scalar(if accepted {
+            "INSERT INTO uploads (level_id, user_id, image_path, accepted, phash, quality_flags, quality_score, accepted_time, accepted_by)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, NOW(), $2) RETURNING id"
         } else {
-            "INSERT INTO uploads (level_id, user_id, image_path, accepted)
-                     VALUES ($1, $2, $3, $4)"
+            "INSERT INTO uploads (level_id, user_id, image_path, accepted, phash, quality_flags, quality_score)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id"
         })
         .bind(level_id)
         .bind(user_id)
         .bind(image_path)
         .bind(accepted)
+        .bind(analysis.phash)
+        .bind(&analysis.quality_flags)
+        .bind(analysis.quality_score)
+        .fetch_one(&*self.pool)
+        .await;
+        self.hashes.invalidate();
+        id
+    }
+
+    // Closest match for a new upload among the level's current thumbnail and the uploads
+    // of the same user that were rejected for this level
+    pub async fn find_duplicate_upload(
+        &self,
+        level_id: i64,
+        user_id: i64,
+        phash: i64,
+        max_distance: i64,
+    ) -> Result<Option<DuplicateUpload>, sqlx::Error> {
+        sqlx::query_as::<_, DuplicateUpload>(
+            "SELECT id, accepted, bit_count((phash # $3)::BIT(64)) AS distance
+                 FROM uploads
+                 WHERE level_id = $1 AND phash IS NOT NULL
+                   AND (
+                     id = (SELECT id FROM current_uploads WHERE level_id = $1)
+                     OR (user_id = $2 AND accepted = FALSE AND accepted_time IS NOT NULL)
+                   )
+                   AND bit_count((phash # $3)::BIT(64)) <= $4
+                 ORDER BY distance, accepted DESC
+                 LIMIT 1",
+        )
+        .bind(level_id)
+        .bind(user_id)
+        .bind(phash)
+        .bind(max_distance)
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    pub async fn set_thumbnail_metadata(
+        &self,
+        id: i64,
+        metadata: &ThumbnailMetadata,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE uploads
+                 SET placeholder = $1, dominant_color = $2, palette = $3, phash = COALESCE(phash, $4)
+                 WHERE id = $5",
+        )
+        .bind(&metadata.placeholder)
+        .bind(&metadata.dominant_color)
+        .bind(&metadata.palette)
+        .bind(metadata.phash)
+        .bind(id)
         .execute(&*self.pool)
         .await?;
+        self.hashes.invalidate();
         Ok(())
     }
 
+    // Current thumbnails that were accepted before their metadata was computed on upload,
+    // as (level_id, upload_id)
+    pub async fn get_uploads_missing_metadata(&self) -> Result<Vec<(i64, i64)>, sqlx::Error> {
+        sqlx::query_as::<_, (i64, i64)>(
+            "SELECT current_uploads.level_id, current_uploads.id
+                 FROM current_uploads
+                 JOIN uploads ON uploads.id = current_uploads.id
+                 WHERE uploads.placeholder IS NULL OR uploads.palette IS NULL OR uploads.phash IS NULL
+                 ORDER BY current_uploads.level_id",
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Placeholders of the current thumbnails for the given levels
+    pub async fn get_placeholders(
+        &self,
+        level_ids: &[i64],
+    ) -> Result<Vec<LevelPlaceholder>, sqlx::Error> {
+        sqlx::query_as::<_, LevelPlaceholder>(
+            "SELECT current_uploads.level_id, uploads.placeholder
+                 FROM current_uploads
+                 JOIN uploads ON uploads.id = current_uploads.id
+                 WHERE current_uploads.level_id = ANY($1)",
+        )
+        .bind(level_ids)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
     // pub async fn get_pending_uploads(&self) -> Result<Vec<PendingUpload>, sqlx::Error> {
     //     sqlx::query_as::<_, PendingUpload>(
     //         "SELECT uploads.id, user_id, username, level_id, accepted, upload_time FROM uploads
@@ -301,18 +822,14 @@ impl AppState {
         }
     }
 
-    fn is_image_uploaded(level_id: i64) -> bool {
-        let image_path = format!("thumbnails/{}.webp", level_id);
-        Path::new(&image_path).exists()
-    }
-
     pub async fn get_pending_uploads_paginated(
         &self,
         options: PendingQueryOptions,
     ) -> Result<PendingUploadsPage, sqlx::Error> {
         if options.replacement_only || options.new_only {
             let mut data_builder = QueryBuilder::new(
-                "SELECT uploads.id, user_id, username, level_id, accepted, upload_time FROM uploads
+                "SELECT uploads.id, user_id, username, level_id, accepted, upload_time,
+                        quality_flags, quality_score FROM uploads
                  LEFT JOIN users ON users.id = user_id
                  WHERE accepted = FALSE AND accepted_time IS NULL",
             );
@@ -323,7 +840,7 @@ impl AppState {
                 data_builder.build_query_as::<PendingUpload>().fetch_all(&*self.pool).await?;
 
             all_uploads.retain(|upload| {
-                let is_uploaded = Self::is_image_uploaded(upload.level_id);
+                let is_uploaded = self.index.contains(upload.level_id);
                 if options.replacement_only { is_uploaded } else { !is_uploaded }
             });
 
@@ -339,7 +856,8 @@ impl AppState {
             let offset = ((options.page.saturating_sub(1)) as i64) * per_page;
 
             let mut data_builder = QueryBuilder::new(
-                "SELECT uploads.id, user_id, username, level_id, accepted, upload_time FROM uploads
+                "SELECT uploads.id, user_id, username, level_id, accepted, upload_time,
+                        quality_flags, quality_score FROM uploads
                  LEFT JOIN users ON users.id = user_id
                  WHERE accepted = FALSE AND accepted_time IS NULL",
             );
@@ -386,7 +904,8 @@ impl AppState {
         user_id: i64,
     ) -> Result<Vec<PendingUpload>, sqlx::Error> {
         sqlx::query_as::<_, PendingUpload>(
-            "SELECT uploads.id, user_id, username, accepted, upload_time FROM uploads
+            "SELECT uploads.id, user_id, username, level_id, accepted, upload_time,
+                    quality_flags, quality_score FROM uploads
               LEFT JOIN users ON users.id = user_id
               WHERE accepted = FALSE AND accepted_time IS NULL AND user_id = $1
               ORDER BY upload_time",
@@ -398,7 +917,8 @@ impl AppState {
 
     pub async fn get_pending_upload(&self, id: i64) -> Result<PendingUpload, sqlx::Error> {
         sqlx::query_as::<_, PendingUpload>(
-            "SELECT uploads.id, user_id, username, level_id, accepted, upload_time FROM uploads
+            "SELECT uploads.id, user_id, username, level_id, accepted, upload_time,
+                    quality_flags, quality_score FROM uploads
               LEFT JOIN users ON users.id = user_id
               WHERE accepted = FALSE AND accepted_time IS NULL AND uploads.id = $1",
         )
@@ -407,6 +927,59 @@ impl AppState {
         .await
     }
 
+    // Hashes of all current thumbnails and pending uploads
+    async fn hash_entries(&self) -> Result<Arc<Vec<HashEntry>>, sqlx::Error> {
+        if let Some(entries) = self.hashes.get() {
+            return Ok(entries);
+        }
+
+        let generation = self.hashes.generation();
+        let entries = sqlx::query_as::<_, HashEntry>(
+            "SELECT id AS upload_id, level_id, phash, accepted = FALSE AS pending
+                 FROM uploads
+                 WHERE phash IS NOT NULL
+                   AND (
+                     id IN (SELECT id FROM current_uploads)
+                     OR (accepted = FALSE AND accepted_time IS NULL)
+                   )",
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+        Ok(self.hashes.store(entries, generation))
+    }
+
+    // Current thumbnails and pending uploads of other levels that nearly match the given
+    // pending uploads, closest first and at most MAX_CROSS_LEVEL_MATCHES for each
+    pub async fn get_cross_level_matches(
+        &self,
+        pending_ids: &[i64],
+        max_distance: i64,
+    ) -> Result<Vec<CrossLevelMatch>, sqlx::Error> {
+        let entries = self.hash_entries().await?;
+        let mut matches = Vec::new();
+
+        for pending in entries.iter().filter(|entry| pending_ids.contains(&entry.upload_id)) {
+            let mut found: Vec<CrossLevelMatch> = entries
+                .iter()
+                .filter(|other| other.level_id != pending.level_id)
+                .map(|other| CrossLevelMatch {
+                    pending_id: pending.upload_id,
+                    level_id: other.level_id,
+                    upload_id: other.upload_id,
+                    pending: other.pending,
+                    distance: dhash::distance(pending.phash, other.phash),
+                })
+                .filter(|found| found.distance <= max_distance)
+                .collect();
+
+            found.sort_unstable_by_key(|found| (found.distance, found.level_id, found.upload_id));
+            found.truncate(MAX_CROSS_LEVEL_MATCHES);
+            matches.extend(found);
+        }
+
+        Ok(matches)
+    }
+
     pub async fn accept_upload(
         &self,
         id: i64,
@@ -423,6 +996,7 @@ impl AppState {
              .bind(id)
              .execute(&*self.pool)
              .await?;
+        self.hashes.invalidate();
         Ok(())
     }
 
@@ -437,18 +1011,9 @@ impl AppState {
                  COUNT(DISTINCT uploads.level_id) FILTER (WHERE uploads.accepted = TRUE) AS accepted_level_count,
                  (
                    SELECT COUNT(*)
-                   FROM (
-                     SELECT u.level_id
-                     FROM uploads u
-                     WHERE u.accepted = TRUE
-                     AND u.user_id = users.id
-                     AND u.upload_time = (
-                       SELECT MAX(u2.upload_time)
-                       FROM uploads u2
-                       WHERE u2.level_id = u.level_id
-                         AND u2.accepted = TRUE
-                     )
-                   ) active_levels
+                   FROM current_uploads
+                   JOIN uploads u ON u.id = current_uploads.id
+                   WHERE u.user_id = users.id
                  ) AS active_thumbnail_count
                FROM users
                LEFT JOIN uploads ON users.id = uploads.user_id
@@ -0,0 +1,33 @@
+use image::RgbImage;
+
+// Difference hash: the image is shrunk to 9x8 grayscale pixels, and each bit tells whether a
+// pixel is brighter than its right neighbour. Re-encoded, rescaled or slightly edited copies
+// of an image end up within a few bits of each other.
+const HASH_WIDTH: u32 = 8;
+const HASH_HEIGHT: u32 = 8;
+
+pub fn hash(image: &RgbImage) -> i64 {
+    let gray = image::imageops::grayscale(image);
+    let small = image::imageops::resize(
+        &gray,
+        HASH_WIDTH + 1,
+        HASH_HEIGHT,
+        image::imageops::FilterType::Triangle,
+    );
+
+    let mut hash = 0u64;
+    for y in 0..HASH_HEIGHT {
+        for x in 0..HASH_WIDTH {
+            let brighter = small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+
+    // stored in a BIGINT column
+    hash as i64
+}
+
+// Number of bits that differ between two hashes
+pub fn distance(a: i64, b: i64) -> i64 {
+    (a ^ b).count_ones() as i64
+}
@@ -0,0 +1,139 @@
+use crate::image_cache;
+use crate::thumbnail_index::ThumbnailIndex;
+use image::RgbImage;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use webp::{Encoder, WebPConfig};
+
+// The savings are estimated by scanning the cache, which is too slow to do on every request
+const STATS_MAX_AGE: Duration = Duration::from_secs(600);
+static STATS: LazyLock<Mutex<Option<(Instant, EncodingStats)>>> = LazyLock::new(Default::default);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct EncodingProfile {
+    pub lossless: bool,
+    pub quality: f32, // 0-100, for lossless this controls compression effort instead
+    pub method: i32,  // 0 (fastest) to 6 (slowest, smallest output)
+}
+
+impl EncodingProfile {
+    const fn lossy(quality: f32) -> Self {
+        Self {
+            lossless: false,
+            quality,
+            method: 4,
+        }
+    }
+
+    const fn lossless() -> Self {
+        Self {
+            lossless: true,
+            quality: 75.0,
+            method: 4,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=100.0).contains(&self.quality) {
+            return Err("Quality must be between 0 and 100".to_string());
+        }
+
+        if !(0..=6).contains(&self.method) {
+            return Err("Method must be between 0 and 6".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn encode(&self, image: &RgbImage) -> Result<Vec<u8>, String> {
+        let mut config = WebPConfig::new().map_err(|_| "Failed to create WebP config")?;
+        config.lossless = self.lossless as i32;
+        config.quality = self.quality;
+        config.method = self.method;
+
+        let (width, height) = image.dimensions();
+        Encoder::from_rgb(image, width, height)
+            .encode_advanced(&config)
+            .map(|data| data.to_vec())
+            .map_err(|e| format!("Failed to encode WebP: {:?}", e))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncodingSettings {
+    pub high: EncodingProfile,
+    pub medium: EncodingProfile,
+    pub small: EncodingProfile,
+}
+
+impl Default for EncodingSettings {
+    fn default() -> Self {
+        Self {
+            high: EncodingProfile::lossless(),
+            medium: EncodingProfile::lossy(90.0),
+            small: EncodingProfile::lossy(80.0),
+        }
+    }
+}
+
+impl EncodingSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        self.high.validate()?;
+        self.medium.validate()?;
+        self.small.validate()
+    }
+
+    // Custom sizes use the profile of the smallest tier they fit in
+    pub fn for_width(&self, width: u32) -> EncodingProfile {
+        match width {
+            0..=640 => self.small,
+            641..=1280 => self.medium,
+            _ => self.high,
+        }
+    }
+}
+
+// Size of the cached WebP variants vs. what lossless encoding would have produced. The lossless
+// size of a variant is estimated from the original thumbnail of its level, scaled by pixel count.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct EncodingStats {
+    pub encoded_bytes: u64,
+    pub lossless_bytes: u64,
+    pub saved_bytes: u64,
+}
+
+async fn measure(index: &ThumbnailIndex) -> EncodingStats {
+    let variants =
+        tokio::task::spawn_blocking(image_cache::webp_variants).await.unwrap_or_default();
+    let original_pixels = 1920.0 * 1080.0;
+
+    let mut stats = EncodingStats::default();
+    for variant in variants {
+        let Some(original_size) = index.size(variant.level_id) else {
+            continue;
+        };
+
+        stats.encoded_bytes += variant.size;
+        stats.lossless_bytes += (original_size as f64 * variant.pixels as f64 / original_pixels)
+            .max(variant.size as f64) as u64;
+    }
+
+    stats.saved_bytes = stats.lossless_bytes - stats.encoded_bytes;
+    stats
+}
+
+pub async fn stats(index: &ThumbnailIndex) -> EncodingStats {
+    let mut cached = STATS.lock().await;
+    if let Some((measured_at, stats)) = *cached
+        && measured_at.elapsed() < STATS_MAX_AGE
+    {
+        return stats;
+    }
+
+    let stats = measure(index).await;
+    *cached = Some((Instant::now(), stats));
+    stats
+}
@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+// Perceptual hashes of every current thumbnail and pending upload, so images reused across
+// levels can be found without comparing every pair of uploads in the database. Loaded on
+// first use and dropped whenever an upload is added, accepted, rejected, reverted or deleted.
+#[derive(Debug, Default)]
+pub struct HashIndex {
+    entries: RwLock<Option<Arc<Vec<HashEntry>>>>,
+    generation: AtomicU64, // changes on every invalidation, so stale loads are never kept
+}
+
+#[derive(Debug, Clone, Copy, sqlx::FromRow)]
+pub struct HashEntry {
+    pub upload_id: i64,
+    pub level_id: i64,
+    pub phash: i64,
+    pub pending: bool,
+}
+
+impl HashIndex {
+    pub fn get(&self) -> Option<Arc<Vec<HashEntry>>> {
+        self.entries.read().unwrap().clone()
+    }
+
+    // Has to be read before loading the entries that are passed to store
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    // Keeps the entries unless the index was invalidated while they were being loaded
+    pub fn store(&self, entries: Vec<HashEntry>, generation: u64) -> Arc<Vec<HashEntry>> {
+        let entries = Arc::new(entries);
+        let mut current = self.entries.write().unwrap();
+        if self.generation.load(Ordering::Acquire) == generation {
+            *current = Some(entries.clone());
+        }
+        entries
+    }
+
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        *self.entries.write().unwrap() = None;
+    }
+}
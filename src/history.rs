@@ -0,0 +1,101 @@
+use std::path::{Path, PathBuf};
+
+// Every accepted thumbnail is kept as history/{level_id}/{upload_id}.webp,
+// while thumbnails/{level_id}.webp always holds the current one. The two are hard links to
+// the same file where possible, so the current thumbnail must only ever be replaced by
+// renaming a new file over it, never written to in place.
+const HISTORY_DIR: &str = "history";
+
+pub fn version_path(level_id: i64, upload_id: i64) -> PathBuf {
+    PathBuf::from(format!("{}/{}/{}.webp", HISTORY_DIR, level_id, upload_id))
+}
+
+pub fn current_path(level_id: i64) -> PathBuf {
+    PathBuf::from(format!("thumbnails/{}.webp", level_id))
+}
+
+fn temp_path(level_id: i64) -> PathBuf {
+    current_path(level_id).with_extension(format!("{}.tmp", rand::random::<u32>()))
+}
+
+// Falls back to copying where hard links aren't supported
+async fn link_or_copy(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    if tokio::fs::hard_link(from, to).await.is_err() {
+        tokio::fs::copy(from, to).await?;
+    }
+    Ok(())
+}
+
+// Adds the current thumbnail of a level to its history
+pub async fn archive(level_id: i64, upload_id: i64) -> Result<(), std::io::Error> {
+    let path = version_path(level_id, upload_id);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    link_or_copy(&current_path(level_id), &path).await
+}
+
+// Thumbnails accepted before history was kept only exist as the current file, so they are
+// archived right before anything replaces or removes it
+pub async fn archive_current(level_id: i64, upload_id: i64) -> Result<(), std::io::Error> {
+    if tokio::fs::try_exists(version_path(level_id, upload_id)).await? {
+        return Ok(());
+    }
+
+    match archive(level_id, upload_id).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()), // nothing to keep
+        result => result,
+    }
+}
+
+// An archived version that is ready to replace the current thumbnail
+pub struct StagedRestore {
+    level_id: i64,
+    tmp_path: PathBuf,
+}
+
+impl StagedRestore {
+    pub async fn apply(self) -> Result<(), std::io::Error> {
+        if let Err(e) = tokio::fs::rename(&self.tmp_path, current_path(self.level_id)).await {
+            tokio::fs::remove_file(&self.tmp_path).await.unwrap_or(());
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub async fn discard(self) {
+        tokio::fs::remove_file(&self.tmp_path).await.unwrap_or(());
+    }
+}
+
+// Prepares an archived version to become the current thumbnail, without touching it yet
+pub async fn stage_restore(level_id: i64, upload_id: i64) -> Result<StagedRestore, std::io::Error> {
+    let tmp_path = temp_path(level_id);
+    link_or_copy(&version_path(level_id, upload_id), &tmp_path).await?;
+    Ok(StagedRestore { level_id, tmp_path })
+}
+
+// Makes an archived version the current thumbnail of a level again
+pub async fn restore(level_id: i64, upload_id: i64) -> Result<(), std::io::Error> {
+    stage_restore(level_id, upload_id).await?.apply().await
+}
+
+// Replaces the current thumbnail of a level with new image data
+pub async fn replace_current(level_id: i64, data: &[u8]) -> Result<(), std::io::Error> {
+    let tmp_path = temp_path(level_id);
+    tokio::fs::write(&tmp_path, data).await?;
+    if let Err(e) = tokio::fs::rename(&tmp_path, current_path(level_id)).await {
+        tokio::fs::remove_file(&tmp_path).await.unwrap_or(());
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Removes an archived version, if it was ever archived
+pub async fn remove(level_id: i64, upload_id: i64) -> Result<(), std::io::Error> {
+    match tokio::fs::remove_file(version_path(level_id, upload_id)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
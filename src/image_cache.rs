@@ -0,0 +1,130 @@
+use std::path::PathBuf;
+use tracing::warn;
+
+// Generated variants are stored as cache/{level_id}/{upload_id}/{key}, so a new accepted
+// upload never picks up files that were rendered from the image it replaced.
+const CACHE_DIR: &str = "cache";
+
+fn level_dir(level_id: i64) -> PathBuf {
+    PathBuf::from(format!("{}/{}", CACHE_DIR, level_id))
+}
+
+fn variant_path(level_id: i64, upload_id: i64, key: &str) -> PathBuf {
+    level_dir(level_id).join(upload_id.to_string()).join(key)
+}
+
+pub async fn read(level_id: i64, upload_id: i64, key: &str) -> Option<Vec<u8>> {
+    tokio::fs::read(variant_path(level_id, upload_id, key)).await.ok()
+}
+
+pub async fn store(level_id: i64, upload_id: i64, key: &str, data: &[u8]) {
+    let path = variant_path(level_id, upload_id, key);
+    if let Some(parent) = path.parent()
+        && let Err(e) = tokio::fs::create_dir_all(parent).await
+    {
+        warn!("Failed to create cache directory for level {}: {}", level_id, e);
+        return;
+    }
+
+    // write to a temporary file first, so readers never see a partially written variant
+    let temp_path = path.with_extension(format!("{}.tmp", rand::random::<u32>()));
+    if let Err(e) = tokio::fs::write(&temp_path, data).await {
+        warn!("Failed to write cached variant {} for level {}: {}", key, level_id, e);
+        return;
+    }
+
+    if let Err(e) = tokio::fs::rename(&temp_path, &path).await {
+        warn!("Failed to store cached variant {} for level {}: {}", key, level_id, e);
+        tokio::fs::remove_file(&temp_path).await.unwrap_or(());
+    }
+}
+
+// Removes all cached variants of a level and returns the keys that were cached
+pub async fn invalidate(level_id: i64) -> Vec<String> {
+    let dir = level_dir(level_id);
+    let mut keys = Vec::new();
+
+    if let Ok(mut uploads) = tokio::fs::read_dir(&dir).await {
+        while let Ok(Some(upload_dir)) = uploads.next_entry().await {
+            let Ok(mut variants) = tokio::fs::read_dir(upload_dir.path()).await else {
+                continue;
+            };
+            while let Ok(Some(variant)) = variants.next_entry().await {
+                if let Some(name) = variant.file_name().to_str()
+                    && !keys.iter().any(|key| key == name)
+                {
+                    keys.push(name.to_string());
+                }
+            }
+        }
+    }
+
+    if let Err(e) = tokio::fs::remove_dir_all(&dir).await
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        warn!("Failed to invalidate cached variants for level {}: {}", level_id, e);
+    }
+
+    keys
+}
+
+// Removes every cached variant of every level
+pub async fn clear() {
+    let Ok(mut levels) = tokio::fs::read_dir(CACHE_DIR).await else {
+        return;
+    };
+
+    while let Ok(Some(level)) = levels.next_entry().await {
+        if let Err(e) = tokio::fs::remove_dir_all(level.path()).await {
+            warn!("Failed to clear cached variants in {:?}: {}", level.path(), e);
+        }
+    }
+}
+
+pub struct CachedVariant {
+    pub level_id: i64,
+    pub size: u64,
+    pub pixels: u64,
+}
+
+// Walks the whole cache directory and reads the header of every WebP variant, so this blocks
+pub fn webp_variants() -> Vec<CachedVariant> {
+    let mut variants = Vec::new();
+    let Ok(levels) = std::fs::read_dir(CACHE_DIR) else {
+        return variants;
+    };
+
+    for level in levels.flatten() {
+        let Some(Ok(level_id)) = level.file_name().to_str().map(str::parse::<i64>) else {
+            continue;
+        };
+        let Ok(uploads) = std::fs::read_dir(level.path()) else {
+            continue;
+        };
+
+        for upload in uploads.flatten() {
+            let Ok(files) = std::fs::read_dir(upload.path()) else {
+                continue;
+            };
+
+            for file in files.flatten() {
+                let path = file.path();
+                if path.extension().is_none_or(|extension| extension != "webp") {
+                    continue;
+                }
+
+                if let Ok(metadata) = file.metadata()
+                    && let Ok((width, height)) = image::image_dimensions(&path)
+                {
+                    variants.push(CachedVariant {
+                        level_id,
+                        size: metadata.len(),
+                        pixels: width as u64 * height as u64,
+                    });
+                }
+            }
+        }
+    }
+
+    variants
+}
@@ -1,6 +1,6 @@
+use axum::extract::{DefaultBodyLimit, State};
 use axum::response::Response;
-use axum::{Router, routing::get, routing::post};
-use std::path::Path;
+use axum::{Router, routing::delete, routing::get, routing::post};
 use tower_http::cors;
 use tower_http::services::{ServeDir, ServeFile};
 use tracing::info;
@@ -8,12 +8,24 @@ use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::filter::EnvFilter;
 
 mod auth;
+mod bloom;
+mod blurhash;
 mod cache_controller;
 mod database;
+mod dhash;
+mod encoding;
+mod hash_index;
+mod history;
+mod image_cache;
+mod palette;
+mod quality;
+mod render_pool;
 mod routes;
+mod smartcrop;
+mod thumbnail_index;
 mod util;
 
-use routes::{admin, login, thumbnail, upload, user};
+use routes::{admin, feeds, login, thumbnail, upload, user};
 
 #[tokio::main]
 async fn main() {
@@ -36,6 +48,8 @@ async fn main() {
     // setup directories
     tokio::fs::create_dir_all("thumbnails").await.unwrap();
     tokio::fs::create_dir_all("uploads").await.unwrap();
+    tokio::fs::create_dir_all("cache").await.unwrap();
+    tokio::fs::create_dir_all("history").await.unwrap();
 
     let cors = cors::CorsLayer::new()
         .allow_origin(cors::Any)
@@ -43,6 +57,7 @@ async fn main() {
         .allow_headers(cors::Any);
 
     let db = database::get_db().await;
+    tokio::spawn(upload::backfill_thumbnail_metadata(db.clone()));
 
     let app = Router::new()
         .route("/stats", get(get_stats))
@@ -50,8 +65,24 @@ async fn main() {
         .route("/thumbnail/{id}", get(thumbnail::image_handler_default))
         .route("/thumbnail/{id}/{res}", get(thumbnail::image_handler_with_res))
         .route("/thumbnail/{id}/info", get(thumbnail::thumbnail_info_handler))
+        .route("/thumbnail/{id}/blur", get(thumbnail::blur_handler))
+        .route("/thumbnail/{id}/history", get(thumbnail::history_handler))
+        .route("/thumbnail/{id}/version/{upload_id}", get(thumbnail::version_handler))
         .route("/thumbnail/random", get(thumbnail::random_handler))
         .route("/thumbnail/random/{res}", get(thumbnail::random_res_handler))
+        .route("/thumbnail/daily", get(thumbnail::daily_handler))
+        .route("/thumbnail/daily/{res}", get(thumbnail::daily_res_handler))
+        // /thumbnails
+        .route("/thumbnails/info", get(thumbnail::batch_info_handler))
+        .route("/thumbnails/info", post(thumbnail::batch_info_post_handler))
+        .route("/thumbnails/placeholders", get(thumbnail::placeholders_handler))
+        .route("/thumbnails/exists", get(thumbnail::exists_handler))
+        .route("/thumbnails/exists", post(thumbnail::exists_post_handler))
+        .route("/thumbnails/bloom", get(thumbnail::bloom_handler))
+        .route("/thumbnails/recent", get(thumbnail::recent_handler))
+        // /feeds
+        .route("/feeds/thumbnails.atom", get(feeds::atom_handler))
+        .route("/feeds/thumbnails.rss", get(feeds::rss_handler))
         // /auth
         .route("/auth/login", post(login::login))
         .route("/auth/discord", get(login::discord_oauth_handler))
@@ -66,7 +97,10 @@ async fn main() {
         // .route("/user/me/uploads", get(routes::user::get_my_uploads))
         // .route("/user/{id}/uploads", get(routes::user::get_user_uploads))
         // /upload
-        .route("/upload/{id}", post(upload::upload))
+        .route(
+            "/upload/{id}",
+            post(upload::upload).layer(DefaultBodyLimit::max(upload::max_upload_size())),
+        )
         // /pending
         .route("/pending/{id}/image", get(upload::get_pending_image))
         .route("/pending", get(upload::get_all_pending_uploads))
@@ -77,11 +111,13 @@ async fn main() {
         // /admin
         .route("/admin/settings", get(admin::get_settings))
         .route("/admin/settings", post(admin::update_settings))
+        .route("/admin/thumbnail/{id}", delete(admin::delete_thumbnail))
+        .route("/admin/thumbnail/{id}/revert", post(admin::revert_thumbnail))
+        .route("/admin/thumbnail/{id}/focal", post(admin::set_focal_point))
         // .route("/admin/users", get(routes::admin::get_users))
         // .route("/admin/user/:id", get(routes::admin::get_user_by_id))
         // .route("/admin/user/:id", patch(routes::admin::update_user))
         // .route("/admin/ban/:id", post(routes::admin::ban_user))
-        // .route("/admin/thumbnail/:id", delete(routes::admin::delete_thumbnail))
         .with_state(db)
         .layer(cors)
         .fallback_service(ServeDir::new("dist").fallback(ServeFile::new("dist/index.html")));
@@ -93,25 +129,9 @@ async fn main() {
     axum::serve(listener, app).await.unwrap();
 }
 
-async fn get_dir_stats(path: &Path) -> Result<(u64, usize), std::io::Error> {
-    let mut entries = tokio::fs::read_dir(path).await?;
-    let mut total_size = 0;
-    let mut file_count = 0;
-
-    while let Some(entry) = entries.next_entry().await? {
-        let metadata = entry.metadata().await?;
-        file_count += 1;
-        total_size += metadata.len();
-    }
-
-    Ok((total_size, file_count))
-}
-
-async fn get_stats() -> Response {
-    let (storage_size, thumbnails_count) = match get_dir_stats(Path::new("thumbnails")).await {
-        Ok((size, count)) => (size, count),
-        Err(_) => (0, 0),
-    };
+async fn get_stats(State(db): State<database::AppState>) -> Response {
+    let storage_size = db.index.total_size();
+    let thumbnails_count = db.index.len();
 
     let users_per_month = 3292188; // TODO: Fetch this from Cloudflare API
 
@@ -121,6 +141,7 @@ async fn get_stats() -> Response {
             "storage": storage_size,
             "thumbnails": thumbnails_count,
             "users_per_month": users_per_month,
+            "encoding": encoding::stats(&db.index).await,
         }),
     )
 }
@@ -0,0 +1,120 @@
+use image::RgbImage;
+
+// Median cut quantization over a small copy of the image
+const PALETTE_SIZE: usize = 5;
+const SAMPLE_WIDTH: u32 = 64;
+const SAMPLE_HEIGHT: u32 = 36;
+
+struct ColorBox {
+    pixels: Vec<[u8; 3]>,
+}
+
+impl ColorBox {
+    // Returns the channel with the widest range and that range
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..3)
+            .map(|channel| {
+                let (min, max) = self.pixels.iter().fold((u8::MAX, u8::MIN), |(min, max), p| {
+                    (min.min(p[channel]), max.max(p[channel]))
+                });
+                (channel, max - min)
+            })
+            .max_by_key(|&(_, range)| range)
+            .unwrap()
+    }
+
+    fn split(mut self) -> (ColorBox, ColorBox) {
+        let (channel, _) = self.widest_channel();
+        self.pixels.sort_unstable_by_key(|pixel| pixel[channel]);
+        let upper = self.pixels.split_off(self.pixels.len() / 2);
+        (self, ColorBox { pixels: upper })
+    }
+
+    fn average(&self) -> [u8; 3] {
+        let mut sum = [0u64; 3];
+        for pixel in &self.pixels {
+            for (total, value) in sum.iter_mut().zip(pixel) {
+                *total += *value as u64;
+            }
+        }
+        sum.map(|total| (total / self.pixels.len() as u64) as u8)
+    }
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    a.iter().zip(b).map(|(&x, y)| (x as i32 - y as i32).pow(2) as u32).sum()
+}
+
+fn to_hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+// Returns the palette as hex colors, most common first. The first entry is the dominant color.
+pub fn extract(image: &RgbImage) -> Vec<String> {
+    let sample = image::imageops::thumbnail(image, SAMPLE_WIDTH, SAMPLE_HEIGHT);
+    let mut boxes = vec![ColorBox {
+        pixels: sample.pixels().map(|pixel| pixel.0).collect(),
+    }];
+
+    while boxes.len() < PALETTE_SIZE {
+        // always split the box with the most color variation
+        let Some(index) = boxes
+            .iter()
+            .enumerate()
+            .map(|(index, color_box)| (index, color_box.widest_channel().1))
+            .filter(|&(_, range)| range > 0)
+            .max_by_key(|&(_, range)| range)
+            .map(|(index, _)| index)
+        else {
+            break;
+        };
+
+        let (lower, upper) = boxes.swap_remove(index).split();
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+
+    // Median cut gives every box roughly the same amount of pixels, so rank the colors
+    // by how many pixels are closest to each of them instead
+    let colors: Vec<[u8; 3]> = boxes.iter().map(ColorBox::average).collect();
+    let mut counts = vec![0usize; colors.len()];
+    for pixel in sample.pixels() {
+        let nearest = (0..colors.len()).min_by_key(|&i| distance(colors[i], pixel.0)).unwrap();
+        counts[nearest] += 1;
+    }
+
+    let mut ranked: Vec<usize> = (0..colors.len()).collect();
+    ranked.sort_by_key(|&i| std::cmp::Reverse(counts[i]));
+
+    let mut palette: Vec<String> = Vec::new();
+    for color in ranked.into_iter().map(|i| to_hex(colors[i])) {
+        if !palette.contains(&color) {
+            palette.push(color);
+        }
+    }
+    palette
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_colors_ranked_by_area() {
+        // already at the sample size, so no pixels are blended by scaling
+        let image = RgbImage::from_fn(SAMPLE_WIDTH, SAMPLE_HEIGHT, |x, _| {
+            if x < SAMPLE_WIDTH * 3 / 4 {
+                image::Rgb([255, 0, 0])
+            } else {
+                image::Rgb([0, 0, 255])
+            }
+        });
+        assert_eq!(extract(&image), vec!["#ff0000", "#0000ff"]);
+    }
+
+    #[test]
+    fn single_color() {
+        let image = RgbImage::from_pixel(160, 90, image::Rgb([30, 144, 255]));
+        assert_eq!(extract(&image), vec!["#1e90ff"]);
+    }
+}
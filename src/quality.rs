@@ -0,0 +1,173 @@
+use crate::smartcrop;
+use image::{GrayImage, RgbImage};
+
+// Heuristics for screenshots that are blank, still loading or mostly black bars, measured on
+// a small copy of the image. The score goes from 0 (certainly broken) to 1 (nothing wrong).
+const SAMPLE_WIDTH: u32 = 192;
+const SAMPLE_HEIGHT: u32 = 108;
+// Below this score uploads are refused, anything flagged above it goes to review as usual.
+// Only missing detail and black bars lower the score, brightness alone is just flagged.
+const REJECT_SCORE: f32 = 0.25;
+
+// Luma standard deviation of an image that is a single color, give or take compression noise
+const UNIFORM_STD_DEV: f32 = 3.0;
+// Entropy in bits (out of 4, see smartcrop::entropy) of images with barely any detail
+const LOW_ENTROPY: f32 = 1.5;
+// Rows and columns this dark and flat count as black bars
+const BAR_LUMA: f32 = 24.0;
+const BAR_STD_DEV: f32 = 4.0;
+const LETTERBOX_AREA: f32 = 0.15;
+const DARK_LUMA: f32 = 24.0;
+const BRIGHT_LUMA: f32 = 232.0;
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub flags: Vec<&'static str>,
+    pub score: f32,
+}
+
+impl Report {
+    pub fn is_broken(&self) -> bool {
+        self.score < REJECT_SCORE
+    }
+}
+
+// Mean and standard deviation
+fn stats(values: impl Iterator<Item = u8> + Clone) -> (f32, f32) {
+    let count = values.clone().count() as f32;
+    let mean = values.clone().map(|value| value as f32).sum::<f32>() / count;
+    let variance = values.map(|value| (value as f32 - mean).powi(2)).sum::<f32>() / count;
+    (mean, variance.sqrt())
+}
+
+fn is_bar(values: impl Iterator<Item = u8> + Clone) -> bool {
+    let (mean, std_dev) = stats(values);
+    mean < BAR_LUMA && std_dev < BAR_STD_DEV
+}
+
+// Part of the image covered by black bars on any of the sides
+fn letterbox_area(sample: &GrayImage) -> f32 {
+    let (width, height) = sample.dimensions();
+    let row = |y: u32| is_bar((0..width).map(move |x| sample.get_pixel(x, y).0[0]));
+    let column = |x: u32| is_bar((0..height).map(move |y| sample.get_pixel(x, y).0[0]));
+
+    let top = (0..height).take_while(|&y| row(y)).count() as u32;
+    if top == height {
+        return 1.0;
+    }
+    let bottom = (0..height).rev().take_while(|&y| row(y)).count() as u32;
+    let left = (0..width).take_while(|&x| column(x)).count() as u32;
+    if left == width {
+        return 1.0; // every column is dark enough to be a bar, even if some rows are not
+    }
+    let right = (0..width).rev().take_while(|&x| column(x)).count() as u32;
+
+    let content = (width - left - right) as f32 * (height - top - bottom) as f32;
+    1.0 - content / (width * height) as f32
+}
+
+pub fn check(image: &RgbImage) -> Report {
+    let sample = image::imageops::thumbnail(image, SAMPLE_WIDTH, SAMPLE_HEIGHT);
+    let luma = image::imageops::grayscale(&sample);
+    let pixels = luma.pixels().map(|pixel| pixel.0[0]);
+
+    let (mean, std_dev) = stats(pixels.clone());
+    let entropy = smartcrop::entropy(pixels);
+    let letterbox = letterbox_area(&luma);
+
+    let mut flags = Vec::new();
+    let mut score = 1.0;
+
+    if std_dev < UNIFORM_STD_DEV {
+        flags.push("uniform_color");
+        score = 0.0;
+    }
+    if entropy < LOW_ENTROPY {
+        flags.push("low_detail");
+        score *= entropy / LOW_ENTROPY;
+    }
+    if letterbox > LETTERBOX_AREA {
+        flags.push("letterboxed");
+        score *= 1.0 - letterbox;
+    }
+    if mean < DARK_LUMA {
+        flags.push("too_dark");
+    } else if mean > BRIGHT_LUMA {
+        flags.push("too_bright");
+    }
+
+    Report { flags, score }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    // Deterministic noise in blocks, big enough to survive downscaling to the sample size
+    fn noise(x: u32, y: u32, max: u32) -> u8 {
+        let (x, y) = (x / 16, y / 16);
+        ((x.wrapping_mul(2654435761) ^ y.wrapping_mul(40503)) % (max + 1)) as u8
+    }
+
+    #[test]
+    fn black_frame_is_rejected() {
+        let report = check(&RgbImage::new(1920, 1080));
+        assert!(report.is_broken());
+        assert!(report.flags.contains(&"uniform_color"));
+        assert!(report.flags.contains(&"too_dark"));
+    }
+
+    #[test]
+    fn letterboxed_frame_is_flagged() {
+        // content in the middle half, black bars above and below
+        let image = RgbImage::from_fn(1920, 1080, |x, y| {
+            let value = if (270..810).contains(&y) { noise(x, y, 255) } else { 0 };
+            Rgb([value, value, value])
+        });
+
+        let report = check(&image);
+        assert!(report.flags.contains(&"letterboxed"));
+        assert!(!report.is_broken());
+    }
+
+    #[test]
+    fn mostly_black_bars_are_rejected() {
+        let image = RgbImage::from_fn(1920, 1080, |x, y| {
+            let value = if (480..600).contains(&y) { noise(x, y, 255) } else { 0 };
+            Rgb([value, value, value])
+        });
+
+        let report = check(&image);
+        assert!(report.flags.contains(&"letterboxed"));
+        assert!(report.is_broken());
+    }
+
+    #[test]
+    fn dim_line_on_black_is_rejected() {
+        // a dark loading screen, black apart from one dim full-width line
+        let image = RgbImage::from_fn(1920, 1080, |_, y| {
+            let value = if (540..550).contains(&y) { 40 } else { 0 };
+            Rgb([value, value, value])
+        });
+
+        let report = check(&image);
+        assert!(report.flags.contains(&"letterboxed"));
+        assert!(report.is_broken());
+    }
+
+    #[test]
+    fn dark_detailed_frame_is_only_flagged() {
+        // a night scene, far darker than DARK_LUMA on average
+        let image = RgbImage::from_fn(1920, 1080, |x, y| {
+            let value = noise(x, y, 20);
+            Rgb([value, value, value])
+        });
+
+        let report = check(&image);
+        assert!(report.flags.contains(&"too_dark"));
+        assert!(!report.flags.contains(&"uniform_color"));
+        assert!(!report.flags.contains(&"letterboxed"));
+        assert!(!report.is_broken());
+    }
+}
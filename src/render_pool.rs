@@ -0,0 +1,158 @@
+use crate::util;
+use axum::http::{StatusCode, header};
+use axum::response::Response;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::sync::{OnceCell, Semaphore};
+
+// All CPU-bound image work (decoding, resizing, encoding) goes through here. At most
+// RENDER_WORKERS jobs run at once, and requests are turned away once RENDER_QUEUE_SIZE
+// more are waiting, instead of piling up on the blocking thread pool.
+const RETRY_AFTER_SECONDS: u32 = 2;
+
+struct Limits {
+    workers: usize,
+    queue_size: usize,
+}
+
+static LIMITS: LazyLock<Limits> = LazyLock::new(|| {
+    let workers = dotenv::var("RENDER_WORKERS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()))
+        .max(1);
+    let queue_size = dotenv::var("RENDER_QUEUE_SIZE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(workers * 8);
+    Limits { workers, queue_size }
+});
+
+static SEMAPHORE: LazyLock<Arc<Semaphore>> =
+    LazyLock::new(|| Arc::new(Semaphore::new(LIMITS.workers)));
+
+// Jobs that are running or waiting for a worker
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+type Flight = Arc<OnceCell<Result<Vec<u8>, RenderError>>>;
+static IN_FLIGHT: LazyLock<Mutex<HashMap<String, Flight>>> = LazyLock::new(Default::default);
+
+#[derive(Debug, Clone)]
+pub enum RenderError {
+    Busy,
+    Failed(String),
+}
+
+impl RenderError {
+    pub fn into_response(self) -> Response {
+        match self {
+            RenderError::Busy => {
+                let mut response = util::str_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Server is busy processing images, try again later",
+                );
+                response.headers_mut().insert(header::RETRY_AFTER, RETRY_AFTER_SECONDS.into());
+                response
+            }
+            RenderError::Failed(e) => util::str_response(StatusCode::INTERNAL_SERVER_ERROR, &e),
+        }
+    }
+}
+
+struct QueueSlot;
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        QUEUED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn run_blocking<T, F>(job: F, reject_when_full: bool) -> Result<T, RenderError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, String> + Send + 'static,
+{
+    let queued = QUEUED.fetch_add(1, Ordering::Relaxed);
+    let slot = QueueSlot;
+    if reject_when_full && queued >= LIMITS.workers + LIMITS.queue_size {
+        return Err(RenderError::Busy);
+    }
+
+    let permit = SEMAPHORE.clone().acquire_owned().await.unwrap();
+
+    // The permit moves into the task, so it is only released once the work is actually done,
+    // even if the request that started it goes away
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let _slot = slot;
+        job()
+    })
+    .await
+    .map_err(|e| RenderError::Failed(format!("Task join error: {}", e)))?
+    .map_err(RenderError::Failed)
+}
+
+// For work done on behalf of a request, fails with Busy when too much is queued already
+pub async fn run<T, F>(job: F) -> Result<T, RenderError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, String> + Send + 'static,
+{
+    run_blocking(job, true).await
+}
+
+// For background work that has to happen eventually, waits for a worker however long it takes
+pub async fn run_waiting<T, F>(job: F) -> Result<T, RenderError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, String> + Send + 'static,
+{
+    run_blocking(job, false).await
+}
+
+// Concurrent callers with the same key share a single render. The first caller runs it, and
+// if it goes away before finishing, one of the others takes over.
+pub async fn render_once<F, Fut>(key: String, render: F) -> Result<Vec<u8>, RenderError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Vec<u8>, RenderError>>,
+{
+    let flight = IN_FLIGHT.lock().unwrap().entry(key.clone()).or_default().clone();
+    let result = flight.get_or_init(render).await.clone();
+
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    if in_flight.get(&key).is_some_and(|current| Arc::ptr_eq(current, &flight)) {
+        in_flight.remove(&key);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn panicking_job_is_a_server_error() {
+        let result = run(|| -> Result<(), String> { panic!("decoder exploded") }).await;
+        let Err(error @ RenderError::Failed(_)) = result else {
+            panic!("expected a failed job, got {:?}", result);
+        };
+        assert_eq!(error.into_response().status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn failed_job_keeps_its_message() {
+        let result = run(|| -> Result<(), String> { Err("out of memory".to_string()) }).await;
+        assert!(matches!(result, Err(RenderError::Failed(e)) if e == "out of memory"));
+    }
+
+    #[test]
+    fn busy_asks_to_retry() {
+        let response = RenderError::Busy.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], RETRY_AFTER_SECONDS.to_string());
+    }
+}
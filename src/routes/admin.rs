@@ -1,10 +1,13 @@
-use crate::{database, util};
+use crate::encoding::EncodingSettings;
+use crate::routes::thumbnail;
+use crate::{database, history, image_cache, util};
 use axum::Json;
-use axum::extract::State;
+use axum::extract::{Path, State};
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::Response;
 use serde::Deserialize;
 use std::ops::{Deref, DerefMut};
+use tracing::error;
 
 pub async fn admin_middleware(
     headers: &HeaderMap,
@@ -22,6 +25,22 @@ pub async fn admin_middleware(
     }
 }
 
+pub async fn moderator_middleware(
+    headers: &HeaderMap,
+    db: &database::AppState,
+) -> Result<database::User, Response> {
+    let user = util::auth_middleware(headers, db).await?;
+
+    if !matches!(user.role, database::Role::Moderator | database::Role::Admin) {
+        return Err(util::str_response(
+            StatusCode::FORBIDDEN,
+            "Only moderators or admins can perform this action",
+        ));
+    }
+
+    Ok(user)
+}
+
 pub async fn get_settings(headers: HeaderMap, State(db): State<database::AppState>) -> Response {
     match admin_middleware(&headers, &db).await {
         Ok(_) => util::response(
@@ -34,7 +53,8 @@ pub async fn get_settings(headers: HeaderMap, State(db): State<database::AppStat
 
 #[derive(Deserialize, Debug)]
 pub struct UpdateSettingsPayload {
-    pub pause_submissions: bool,
+    pub pause_submissions: Option<bool>,
+    pub encoding: Option<EncodingSettings>,
 }
 
 pub async fn update_settings(
@@ -44,7 +64,29 @@ pub async fn update_settings(
 ) -> Response {
     match admin_middleware(&headers, &db).await {
         Ok(_) => {
-            db.settings.write().await.deref_mut().pause_submissions = payload.pause_submissions;
+            if let Some(Err(e)) = payload.encoding.as_ref().map(EncodingSettings::validate) {
+                return util::str_response(StatusCode::BAD_REQUEST, &e);
+            }
+
+            let encoding_changed = {
+                let mut settings = db.settings.write().await;
+                if let Some(pause_submissions) = payload.pause_submissions {
+                    settings.deref_mut().pause_submissions = pause_submissions;
+                }
+                match payload.encoding {
+                    Some(encoding) => {
+                        settings.deref_mut().encoding = encoding;
+                        true
+                    }
+                    None => false,
+                }
+            };
+
+            // Cached variants were encoded with the old profiles
+            if encoding_changed {
+                image_cache::clear().await;
+            }
+
             match db.save_settings().await {
                 Ok(_) => util::str_response(StatusCode::OK, "Settings updated successfully"),
                 Err(e) => util::str_response(
@@ -56,3 +98,255 @@ pub async fn update_settings(
         Err(resp) => resp,
     }
 }
+
+#[derive(Deserialize, Debug)]
+pub struct RevertThumbnailPayload {
+    pub upload_id: i64,
+}
+
+pub async fn revert_thumbnail(
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    State(db): State<database::AppState>,
+    Json(payload): Json<RevertThumbnailPayload>,
+) -> Response {
+    let user = match moderator_middleware(&headers, &db).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+
+    let level_id = id as i64;
+    let Some(current) = db.get_upload_info(level_id).await else {
+        return util::str_response(StatusCode::NOT_FOUND, "Image not found");
+    };
+
+    if current.id == payload.upload_id {
+        return util::str_response(
+            StatusCode::CONFLICT,
+            "This version is already the current thumbnail",
+        );
+    }
+
+    if db.get_upload_version(level_id, payload.upload_id).await.is_none() {
+        return util::str_response(StatusCode::NOT_FOUND, "Version not found");
+    }
+
+    if let Err(e) = history::archive_current(level_id, current.id).await {
+        return util::str_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Failed to archive the current thumbnail: {}", e),
+        );
+    }
+
+    let restore = match history::stage_restore(level_id, payload.upload_id).await {
+        Ok(restore) => restore,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return util::str_response(
+                StatusCode::NOT_FOUND,
+                "This version is no longer available",
+            );
+        }
+        Err(e) => {
+            return util::str_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("Failed to restore thumbnail: {}", e),
+            );
+        }
+    };
+
+    let tx = match db.restore_upload(payload.upload_id, user.id).await {
+        Ok(tx) => tx,
+        Err(e) => {
+            restore.discard().await;
+            return util::str_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("Failed to update upload entry: {}", e),
+            );
+        }
+    };
+
+    // the database changes are only committed once the current file was replaced
+    if let Err(e) = restore.apply().await {
+        return util::str_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Failed to restore thumbnail: {}", e),
+        );
+    }
+
+    if let Err(e) = tx.commit().await {
+        // put the previous thumbnail back, it was archived above
+        if let Err(e) = history::restore(level_id, current.id).await {
+            error!("Failed to put back the thumbnail of level {}: {}", level_id, e);
+        }
+        return util::str_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Failed to update upload entry: {}", e),
+        );
+    }
+
+    db.hashes.invalidate();
+    db.index.refresh(level_id).await;
+    thumbnail::invalidate(level_id, &[]).await;
+    util::str_response(StatusCode::OK, "Thumbnail reverted successfully")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteThumbnailPayload {
+    pub reason: String,
+}
+
+pub async fn delete_thumbnail(
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    State(db): State<database::AppState>,
+    Json(payload): Json<DeleteThumbnailPayload>,
+) -> Response {
+    let user = match moderator_middleware(&headers, &db).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+
+    let reason = payload.reason.trim();
+    if reason.is_empty() {
+        return util::str_response(StatusCode::BAD_REQUEST, "A reason is required");
+    }
+
+    let level_id = id as i64;
+    let Some(current) = db.get_upload_info(level_id).await else {
+        return util::str_response(StatusCode::NOT_FOUND, "Image not found");
+    };
+
+    // kept until the deletion is committed, so the file can be put back if that fails
+    if let Err(e) = history::archive_current(level_id, current.id).await {
+        return util::str_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Failed to archive the current thumbnail: {}", e),
+        );
+    }
+
+    // The newest older version that still has a file becomes current again. Versions accepted
+    // before history was kept have none, so without one the level is left without a thumbnail.
+    let versions = match db.get_thumbnail_history(level_id).await {
+        Ok(versions) => versions,
+        Err(e) => {
+            return util::str_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("Failed to read thumbnail history: {}", e),
+            );
+        }
+    };
+
+    let mut staged = None;
+    for version in versions.iter().filter(|version| version.id != current.id) {
+        match history::stage_restore(level_id, version.id).await {
+            Ok(restore) => {
+                staged = Some((version.id, restore));
+                break;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return util::str_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("Failed to restore thumbnail: {}", e),
+                );
+            }
+        }
+    }
+
+    let fallback = staged.as_ref().map(|(upload_id, _)| *upload_id);
+    let tx = match db.delete_current_upload(current.id, user.id, reason, fallback).await {
+        Ok(tx) => tx,
+        Err(e) => {
+            if let Some((_, restore)) = staged {
+                restore.discard().await;
+            }
+            return util::str_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("Failed to update upload entry: {}", e),
+            );
+        }
+    };
+
+    // the database changes are only committed once the current file was replaced
+    let result = match staged {
+        Some((_, restore)) => restore.apply().await,
+        None => tokio::fs::remove_file(history::current_path(level_id)).await,
+    };
+    if let Err(e) = result {
+        return util::str_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Failed to remove thumbnail: {}", e),
+        );
+    }
+
+    if let Err(e) = tx.commit().await {
+        // put the deleted thumbnail back from its archive, since it is still the current one
+        if let Err(e) = history::restore(level_id, current.id).await {
+            error!("Failed to put back the thumbnail of level {}: {}", level_id, e);
+        }
+        db.index.refresh(level_id).await;
+        return util::str_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Failed to update upload entry: {}", e),
+        );
+    }
+
+    db.hashes.invalidate();
+    if let Err(e) = history::remove(level_id, current.id).await {
+        error!("Failed to remove version {} of level {}: {}", current.id, level_id, e);
+    }
+
+    db.index.refresh(level_id).await;
+    thumbnail::invalidate(level_id, &[current.id]).await;
+    match fallback {
+        Some(upload_id) => util::str_response(
+            StatusCode::OK,
+            &format!("Thumbnail deleted, restored previous version {}", upload_id),
+        ),
+        None => util::str_response(StatusCode::OK, "Thumbnail deleted successfully"),
+    }
+}
+
+// Both coordinates go from 0 to 1, leave both out to go back to automatic cropping
+#[derive(Deserialize, Debug)]
+pub struct FocalPointPayload {
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+}
+
+pub async fn set_focal_point(
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    State(db): State<database::AppState>,
+    Json(payload): Json<FocalPointPayload>,
+) -> Response {
+    if let Err(resp) = moderator_middleware(&headers, &db).await {
+        return resp;
+    }
+
+    let focal_point = match (payload.x, payload.y) {
+        (Some(x), Some(y)) if (0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y) => Some((x, y)),
+        (None, None) => None,
+        _ => {
+            return util::str_response(
+                StatusCode::BAD_REQUEST,
+                "Focal point needs both x and y between 0 and 1",
+            );
+        }
+    };
+
+    let level_id = id as i64;
+    let Some(current) = db.get_upload_info(level_id).await else {
+        return util::str_response(StatusCode::NOT_FOUND, "Image not found");
+    };
+
+    if let Err(e) = db.set_focal_point(current.id, focal_point).await {
+        return util::str_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Failed to update upload entry: {}", e),
+        );
+    }
+
+    thumbnail::invalidate(level_id, &[]).await;
+    util::str_response(StatusCode::OK, "Focal point updated successfully")
+}
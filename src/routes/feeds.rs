@@ -0,0 +1,201 @@
+use crate::{database, util};
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::Response;
+use serde::Deserialize;
+use std::fmt::Write;
+
+const FEED_SIZE: u32 = 50;
+const FEED_TITLE: &str = "Level Thumbnails";
+const FEED_DESCRIPTION: &str = "Newly accepted level thumbnails";
+// Feed readers poll often, so a few minutes of staleness keeps most of them on the CDN
+const FEED_CACHE_CONTROL: &str = "public, max-age=300";
+
+#[derive(Deserialize, Debug)]
+pub struct FeedQuery {
+    user_id: Option<i64>,
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn home_url() -> String {
+    dotenv::var("HOME_URL").unwrap_or_default().trim_end_matches('/').to_string()
+}
+
+fn entry_title(upload: &database::UploadExtended) -> String {
+    if upload.upload_time > upload.first_upload_time {
+        format!("Thumbnail for level {} was replaced", upload.level_id)
+    } else {
+        format!("New thumbnail for level {}", upload.level_id)
+    }
+}
+
+// HTML shown by feed readers, escaped once more by the caller to fit in the XML
+fn entry_content(upload: &database::UploadExtended, home: &str) -> String {
+    let mut content = format!(
+        "<img src=\"{}/thumbnail/{}/medium\" alt=\"Level {}\"/><p>Uploaded by {}",
+        home,
+        upload.level_id,
+        upload.level_id,
+        escape_xml(&upload.username)
+    );
+    if let Some(accepted_by) = &upload.accepted_by_username {
+        write!(content, ", accepted by {}", escape_xml(accepted_by)).unwrap();
+    }
+    content.push_str("</p>");
+    content
+}
+
+fn self_url(home: &str, extension: &str, query: &FeedQuery) -> String {
+    match query.user_id {
+        Some(user_id) => format!("{}/feeds/thumbnails.{}?user_id={}", home, extension, user_id),
+        None => format!("{}/feeds/thumbnails.{}", home, extension),
+    }
+}
+
+fn build_atom(uploads: &[database::UploadExtended], query: &FeedQuery) -> String {
+    let home = home_url();
+    let self_url = self_url(&home, "atom", query);
+    let updated = uploads.first().and_then(|upload| upload.accepted_time).unwrap_or_default();
+
+    let mut feed = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    feed.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    writeln!(feed, "  <title>{}</title>", FEED_TITLE).unwrap();
+    writeln!(feed, "  <subtitle>{}</subtitle>", FEED_DESCRIPTION).unwrap();
+    writeln!(feed, "  <id>{}</id>", escape_xml(&self_url)).unwrap();
+    writeln!(feed, "  <link rel=\"self\" href=\"{}\"/>", escape_xml(&self_url)).unwrap();
+    writeln!(feed, "  <link href=\"{}/\"/>", home).unwrap();
+    writeln!(feed, "  <updated>{}</updated>", updated.and_utc().to_rfc3339()).unwrap();
+
+    for upload in uploads {
+        let accepted_time = upload.accepted_time.unwrap_or_default().and_utc().to_rfc3339();
+        feed.push_str("  <entry>\n");
+        writeln!(feed, "    <title>{}</title>", entry_title(upload)).unwrap();
+        writeln!(
+            feed,
+            "    <id>{}/thumbnail/{}/version/{}</id>",
+            home, upload.level_id, upload.upload_id
+        )
+        .unwrap();
+        writeln!(feed, "    <link href=\"{}/thumbnail/{}\"/>", home, upload.level_id).unwrap();
+        writeln!(feed, "    <updated>{}</updated>", accepted_time).unwrap();
+        writeln!(feed, "    <author><name>{}</name></author>", escape_xml(&upload.username))
+            .unwrap();
+        writeln!(
+            feed,
+            "    <content type=\"html\">{}</content>",
+            escape_xml(&entry_content(upload, &home))
+        )
+        .unwrap();
+        feed.push_str("  </entry>\n");
+    }
+
+    feed.push_str("</feed>\n");
+    feed
+}
+
+fn build_rss(uploads: &[database::UploadExtended], query: &FeedQuery) -> String {
+    let home = home_url();
+    let self_url = self_url(&home, "rss", query);
+
+    let mut feed = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    feed.push_str("<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n");
+    feed.push_str("<channel>\n");
+    writeln!(feed, "  <title>{}</title>", FEED_TITLE).unwrap();
+    writeln!(feed, "  <link>{}/</link>", home).unwrap();
+    writeln!(feed, "  <description>{}</description>", FEED_DESCRIPTION).unwrap();
+    writeln!(
+        feed,
+        "  <atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>",
+        escape_xml(&self_url)
+    )
+    .unwrap();
+    if let Some(updated) = uploads.first().and_then(|upload| upload.accepted_time) {
+        writeln!(feed, "  <lastBuildDate>{}</lastBuildDate>", util::http_date(updated)).unwrap();
+    }
+
+    for upload in uploads {
+        feed.push_str("  <item>\n");
+        writeln!(feed, "    <title>{}</title>", entry_title(upload)).unwrap();
+        writeln!(feed, "    <link>{}/thumbnail/{}</link>", home, upload.level_id).unwrap();
+        writeln!(
+            feed,
+            "    <guid isPermaLink=\"false\">{}/thumbnail/{}/version/{}</guid>",
+            home, upload.level_id, upload.upload_id
+        )
+        .unwrap();
+        if let Some(accepted_time) = upload.accepted_time {
+            writeln!(feed, "    <pubDate>{}</pubDate>", util::http_date(accepted_time)).unwrap();
+        }
+        writeln!(feed, "    <dc:creator>{}</dc:creator>", escape_xml(&upload.username)).unwrap();
+        writeln!(
+            feed,
+            "    <description>{}</description>",
+            escape_xml(&entry_content(upload, &home))
+        )
+        .unwrap();
+        feed.push_str("  </item>\n");
+    }
+
+    feed.push_str("</channel>\n</rss>\n");
+    feed
+}
+
+async fn handle_feed(
+    query: FeedQuery,
+    headers: &HeaderMap,
+    db: &database::AppState,
+    content_type: &str,
+    build: fn(&[database::UploadExtended], &FeedQuery) -> String,
+) -> Response {
+    let uploads = match db.get_feed_uploads(query.user_id, FEED_SIZE).await {
+        Ok(uploads) => uploads,
+        Err(e) => {
+            return util::str_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("Failed to get thumbnails: {}", e),
+            );
+        }
+    };
+
+    let body = build(&uploads, &query);
+    let etag = util::content_etag(body.as_bytes());
+    let last_modified = uploads.first().and_then(|upload| upload.accepted_time);
+    if util::is_not_modified(headers, &etag, last_modified) {
+        return util::not_modified(&etag, last_modified, FEED_CACHE_CONTROL);
+    }
+
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CACHE_CONTROL, FEED_CACHE_CONTROL)
+        .header(header::ETAG, &etag);
+
+    if let Some(last_modified) = last_modified {
+        builder = builder.header(header::LAST_MODIFIED, util::http_date(last_modified));
+    }
+
+    builder.body(body.into()).unwrap()
+}
+
+pub async fn atom_handler(
+    Query(query): Query<FeedQuery>,
+    headers: HeaderMap,
+    State(db): State<database::AppState>,
+) -> Response {
+    handle_feed(query, &headers, &db, "application/atom+xml; charset=utf-8", build_atom).await
+}
+
+pub async fn rss_handler(
+    Query(query): Query<FeedQuery>,
+    headers: HeaderMap,
+    State(db): State<database::AppState>,
+) -> Response {
+    handle_feed(query, &headers, &db, "application/rss+xml; charset=utf-8", build_rss).await
+}
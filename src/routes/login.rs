@@ -145,7 +145,7 @@ pub async fn discord_oauth_handler(
         }
     };
 
-    if !res.get("access_token").is_some() {
+    if res.get("access_token").is_none() {
         return util::str_response(StatusCode::UNAUTHORIZED, "Invalid Discord code");
     }
 
@@ -194,7 +194,7 @@ pub async fn discord_oauth_handler(
             Response::builder()
                 .status(StatusCode::FOUND)
                 .header("Set-Cookie", format!("auth_token={}; HttpOnly; Path=/; SameSite=Lax; Expires=Fri, 31 Dec 9999 23:59:59 GMT", token))
-                .header("Set-Cookie", format!("auth_role={}; Path=/; SameSite=Lax; Expires=Fri, 31 Dec 9999 23:59:59 GMT", user.role.to_string()))
+                .header("Set-Cookie", format!("auth_role={}; Path=/; SameSite=Lax; Expires=Fri, 31 Dec 9999 23:59:59 GMT", user.role))
                 .header("Location", "/dashboard")
                 .body("Redirecting to dashboard...".into())
                 .unwrap()
@@ -275,18 +275,15 @@ async fn migrate_account(
 
     match db.migrate_user_account(user_id, discord_id).await {
         Ok(user) => {
-            match pending {
-                Ok(uploads) => {
-                    for upload in uploads {
-                        tokio::fs::rename(
-                            format!("uploads/{}_{}.webp", user_id, upload.level_id),
-                            format!("uploads/{}_{}.webp", discord_id, upload.level_id),
-                        )
-                        .await
-                        .unwrap_or(());
-                    }
+            if let Ok(uploads) = pending {
+                for upload in uploads {
+                    tokio::fs::rename(
+                        format!("uploads/{}_{}.webp", user_id, upload.level_id),
+                        format!("uploads/{}_{}.webp", discord_id, upload.level_id),
+                    )
+                    .await
+                    .unwrap_or(());
                 }
-                Err(_) => {}
             }
 
             util::response(
@@ -1,4 +1,5 @@
 pub mod admin;
+pub mod feeds;
 pub mod login;
 pub mod thumbnail;
 pub mod upload;
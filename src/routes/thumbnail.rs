@@ -1,13 +1,50 @@
-use crate::{database, util};
-use axum::extract::{Path, State};
-use axum::http::{StatusCode, header};
+use crate::bloom::BloomFilter;
+use crate::encoding::{EncodingProfile, EncodingSettings};
+use crate::render_pool::{self, RenderError};
+use crate::thumbnail_index::ThumbnailIndex;
+use crate::{cache_controller, database, history, image_cache, smartcrop, util};
+use axum::Json;
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, RawQuery, State};
+use axum::http::{HeaderMap, Method, Request, StatusCode, header, response};
 use axum::response::Response;
-use image::ImageReader;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{DynamicImage, ImageReader, RgbImage};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Cursor;
 use std::path::PathBuf;
-use webp::Encoder;
+use std::sync::{Arc, LazyLock, Mutex};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+// Custom sizes are snapped up to one of these values, so only a bounded set of variants
+// can ever be generated and cached for a level.
+const ALLOWED_SIZES: [u32; 20] = [
+    32, 64, 100, 128, 150, 200, 256, 270, 300, 360, 400, 480, 540, 640, 720, 800, 960, 1080, 1280,
+    1920,
+];
+const MAX_WIDTH: u32 = 1920;
+const MAX_HEIGHT: u32 = 1080;
+const JPEG_QUALITY: u8 = 85;
+const AVIF_QUALITY: u8 = 70;
+const AVIF_SPEED: u8 = 8;
+// Browsers revalidate hourly using the ETag, the CDN keeps images until they're purged
+const IMAGE_CACHE_CONTROL: &str = "public, max-age=3600, s-maxage=31536000, must-revalidate";
+const INFO_CACHE_CONTROL: &str = "no-cache";
+const MAX_BATCH_SIZE: usize = 100;
+const BLOOM_CACHE_CONTROL: &str = "public, max-age=300";
+// Blur radius and brightness are snapped like custom sizes, to bound the cached variants
+const BLUR_RADII: [u32; 6] = [5, 10, 20, 30, 40, 50];
+const BLUR_BRIGHTNESS_STEP: u32 = 10;
+const DEFAULT_BLUR: BlurParams = BlurParams { radius: 20, brightness: 60 };
+const DEFAULT_RECENT_LIMIT: u32 = 24;
+const MAX_RECENT_LIMIT: u32 = 100;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Res {
     #[serde(rename = "high")]
     High, // 1920x1080
@@ -37,19 +74,455 @@ impl std::fmt::Display for Res {
     }
 }
 
-fn image_response(image_data: Vec<u8>, id: u64, upload_info: &database::UploadInfo) -> Response {
-    Response::builder()
-        .header(header::CONTENT_TYPE, "image/webp")
-        .header(header::CONTENT_DISPOSITION, format!("inline; filename=\"{}.webp\"", id))
-        .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable")
-        .header(header::CONTENT_LENGTH, image_data.len())
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    #[default]
+    Cover, // scale and crop to fill the whole box
+    Contain, // scale to fit inside the box, keeping the aspect ratio
+    Fill,    // stretch to the exact box
+}
+
+impl std::fmt::Display for Fit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fit::Cover => write!(f, "cover"),
+            Fit::Contain => write!(f, "contain"),
+            Fit::Fill => write!(f, "fill"),
+        }
+    }
+}
+
+impl std::str::FromStr for Fit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cover" => Ok(Fit::Cover),
+            "contain" => Ok(Fit::Contain),
+            "fill" => Ok(Fit::Fill),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SizeQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<Fit>,
+    format: Option<Format>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CustomSize {
+    width: u32,
+    height: u32,
+    fit: Fit,
+}
+
+fn snap_size(value: u32, max: u32) -> u32 {
+    let value = value.min(max);
+    ALLOWED_SIZES.iter().copied().find(|&size| size >= value).unwrap_or(max)
+}
+
+impl CustomSize {
+    // Missing dimensions are derived from the 16:9 aspect ratio of the original image
+    fn from_query(query: &SizeQuery) -> Option<Self> {
+        let (width, height) = match (query.w, query.h) {
+            (Some(w), Some(h)) => (snap_size(w, MAX_WIDTH), snap_size(h, MAX_HEIGHT)),
+            (Some(w), None) => {
+                let width = snap_size(w, MAX_WIDTH);
+                (width, (width * 9 / 16).max(1))
+            }
+            (None, Some(h)) => {
+                let height = snap_size(h, MAX_HEIGHT);
+                (height * 16 / 9, height)
+            }
+            (None, None) => return None,
+        };
+
+        Some(Self {
+            width,
+            height,
+            fit: query.fit.unwrap_or_default(),
+        })
+    }
+
+    fn query_string(&self) -> String {
+        format!("w={}&h={}&fit={}", self.width, self.height, self.fit)
+    }
+
+    fn cache_key(&self) -> String {
+        format!("{}x{}_{}", self.width, self.height, self.fit)
+    }
+
+    fn from_cache_key(key: &str) -> Option<Self> {
+        let (size, fit) = key.split_once('_')?;
+        let (width, height) = size.split_once('x')?;
+        Some(Self {
+            width: width.parse().ok()?,
+            height: height.parse().ok()?,
+            fit: fit.parse().ok()?,
+        })
+    }
+
+    fn apply(&self, image: DynamicImage, focal_point: Option<(f32, f32)>) -> RgbImage {
+        let filter = image::imageops::FilterType::Lanczos3;
+        match self.fit {
+            Fit::Cover => smartcrop::fill(&image, self.width, self.height, focal_point).to_rgb8(),
+            Fit::Contain => image.resize(self.width, self.height, filter).to_rgb8(),
+            Fit::Fill => image.resize_exact(self.width, self.height, filter).to_rgb8(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct BlurQuery {
+    radius: Option<u32>,
+    brightness: Option<u32>, // percent of the original brightness
+    format: Option<Format>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlurParams {
+    radius: u32,
+    brightness: u32,
+}
+
+impl BlurParams {
+    fn from_query(query: &BlurQuery) -> Self {
+        let radius = query.radius.map(|radius| {
+            BLUR_RADII.iter().copied().find(|&r| r >= radius).unwrap_or(*BLUR_RADII.last().unwrap())
+        });
+        let brightness = query.brightness.map(|brightness| {
+            let brightness = brightness.min(100);
+            (brightness + BLUR_BRIGHTNESS_STEP / 2) / BLUR_BRIGHTNESS_STEP * BLUR_BRIGHTNESS_STEP
+        });
+
+        Self {
+            radius: radius.unwrap_or(DEFAULT_BLUR.radius),
+            brightness: brightness.unwrap_or(DEFAULT_BLUR.brightness),
+        }
+    }
+
+    fn query_string(&self) -> String {
+        format!("radius={}&brightness={}", self.radius, self.brightness)
+    }
+
+    fn cache_key(&self) -> String {
+        format!("blur_{}_{}", self.radius, self.brightness)
+    }
+
+    fn from_cache_key(key: &str) -> Option<Self> {
+        let (radius, brightness) = key.strip_prefix("blur_")?.split_once('_')?;
+        Some(Self {
+            radius: radius.parse().ok()?,
+            brightness: brightness.parse().ok()?,
+        })
+    }
+
+    // Backgrounds lose all detail anyway, so they are blurred and served at the small size
+    fn apply(&self, image: DynamicImage) -> RgbImage {
+        let (width, height) = Res::Small.dimensions();
+        let small = image.resize_exact(width, height, image::imageops::FilterType::Triangle);
+        let mut blurred = image::imageops::fast_blur(&small.to_rgb8(), self.radius as f32);
+
+        let factor = self.brightness as f32 / 100.0;
+        for pixel in blurred.pixels_mut() {
+            for channel in pixel.0.iter_mut() {
+                *channel = (*channel as f32 * factor).round() as u8;
+            }
+        }
+        blurred
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Webp,
+    Avif,
+    Jpeg,
+    Png,
+}
+
+impl Format {
+    fn mime_type(&self) -> &'static str {
+        match self {
+            Format::Webp => "image/webp",
+            Format::Avif => "image/avif",
+            Format::Jpeg => "image/jpeg",
+            Format::Png => "image/png",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+            Format::Jpeg => "jpg",
+            Format::Png => "png",
+        }
+    }
+
+    fn from_extension(extension: &str) -> Option<Self> {
+        [Format::Webp, Format::Avif, Format::Jpeg, Format::Png]
+            .into_iter()
+            .find(|format| format.extension() == extension)
+    }
+
+    // An explicit format= parameter always wins. Otherwise WebP is preferred whenever the
+    // client lists it (or sends nothing specific), falling back to whatever else it accepts.
+    fn negotiate(requested: Option<Format>, headers: &HeaderMap) -> Self {
+        if let Some(format) = requested {
+            return format;
+        }
+
+        let accept = headers.get(header::ACCEPT).and_then(|h| h.to_str().ok()).unwrap_or("");
+        let accepted: Vec<&str> = accept
+            .split(',')
+            .filter_map(|part| {
+                let mut params = part.split(';').map(str::trim);
+                let media_type = params.next()?;
+                let rejected = params.any(|param| {
+                    param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+                });
+                (!rejected).then_some(media_type)
+            })
+            .collect();
+
+        [Format::Webp, Format::Avif, Format::Jpeg, Format::Png]
+            .into_iter()
+            .find(|format| accepted.contains(&format.mime_type()))
+            .unwrap_or(Format::Webp)
+    }
+
+    fn encode(&self, image: &RgbImage, profile: &EncodingProfile) -> Result<Vec<u8>, String> {
+        let mut buffer = Cursor::new(Vec::new());
+
+        let result = match self {
+            Format::Webp => return profile.encode(image),
+            Format::Avif => image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                &mut buffer,
+                AVIF_SPEED,
+                AVIF_QUALITY,
+            )),
+            Format::Jpeg => {
+                image.write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, JPEG_QUALITY))
+            }
+            Format::Png => image.write_with_encoder(PngEncoder::new(&mut buffer)),
+        };
+
+        result.map_err(|e| format!("Failed to encode {}: {}", self, e))?;
+        Ok(buffer.into_inner())
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Webp => write!(f, "webp"),
+            Format::Avif => write!(f, "avif"),
+            Format::Jpeg => write!(f, "jpeg"),
+            Format::Png => write!(f, "png"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FormatQuery {
+    format: Option<Format>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variant {
+    Res(Res),
+    Custom(CustomSize),
+    Blur(BlurParams),
+}
+
+impl Variant {
+    fn cache_key(&self, format: Format) -> String {
+        match self {
+            Variant::Res(res) => format!("{}.{}", res, format.extension()),
+            Variant::Custom(size) => format!("{}.{}", size.cache_key(), format.extension()),
+            Variant::Blur(params) => format!("{}.{}", params.cache_key(), format.extension()),
+        }
+    }
+
+    fn from_cache_key(key: &str) -> Option<(Self, Format)> {
+        let (name, extension) = key.rsplit_once('.')?;
+        let format = Format::from_extension(extension)?;
+        let variant = match name {
+            "high" => Variant::Res(Res::High),
+            "medium" => Variant::Res(Res::Medium),
+            "small" => Variant::Res(Res::Small),
+            _ if name.starts_with("blur_") => Variant::Blur(BlurParams::from_cache_key(name)?),
+            _ => Variant::Custom(CustomSize::from_cache_key(name)?),
+        };
+        Some((variant, format))
+    }
+
+    // URLs (relative to the site root) that may have been answered with this cached file
+    // and are not already part of the fixed purge list
+    fn purge_paths(&self, level_id: i64, format: Format) -> Vec<String> {
+        let format_query = format!("format={}", format);
+        match self {
+            Variant::Res(_) if format == Format::Webp => vec![],
+            Variant::Res(Res::High) => vec![
+                format!("/thumbnail/{}?{}", level_id, format_query),
+                format!("/thumbnail/{}/high?{}", level_id, format_query),
+            ],
+            Variant::Res(res) => vec![format!("/thumbnail/{}/{}?{}", level_id, res, format_query)],
+            Variant::Custom(size) => vec![
+                format!("/thumbnail/{}?{}", level_id, size.query_string()),
+                format!("/thumbnail/{}?{}&{}", level_id, size.query_string(), format_query),
+            ],
+            Variant::Blur(params) => {
+                let mut paths = vec![
+                    format!("/thumbnail/{}/blur?{}", level_id, params.query_string()),
+                    format!(
+                        "/thumbnail/{}/blur?{}&{}",
+                        level_id,
+                        params.query_string(),
+                        format_query
+                    ),
+                ];
+                // the defaults are also served without any parameters
+                if *params == DEFAULT_BLUR && format != Format::Webp {
+                    paths.push(format!("/thumbnail/{}/blur?{}", level_id, format_query));
+                }
+                paths
+            }
+        }
+    }
+
+    fn encoding_profile(&self, settings: &EncodingSettings) -> EncodingProfile {
+        match self {
+            Variant::Res(Res::High) => settings.high,
+            Variant::Res(Res::Medium) => settings.medium,
+            Variant::Res(Res::Small) => settings.small,
+            Variant::Custom(size) => settings.for_width(size.width),
+            Variant::Blur(_) => settings.small,
+        }
+    }
+
+    // Only cropped variants depend on the focal point of the thumbnail
+    fn uses_focal_point(&self) -> bool {
+        matches!(self, Variant::Custom(size) if size.fit == Fit::Cover)
+    }
+
+    fn apply(&self, image: DynamicImage, focal_point: Option<(f32, f32)>) -> RgbImage {
+        match self {
+            Variant::Res(Res::High) => image.to_rgb8(),
+            Variant::Res(res) => {
+                let (width, height) = res.dimensions();
+                image.resize_exact(width, height, image::imageops::FilterType::Lanczos3).to_rgb8()
+            }
+            Variant::Custom(size) => size.apply(image, focal_point),
+            Variant::Blur(params) => params.apply(image),
+        }
+    }
+}
+
+// Drops every cached variant of a level and purges all URLs they could have been served from,
+// along with the URLs of any versions that were removed
+pub async fn invalidate(level_id: i64, removed_versions: &[i64]) {
+    let mut extra_paths: Vec<String> = removed_versions
+        .iter()
+        .map(|upload_id| format!("/thumbnail/{}/version/{}", level_id, upload_id))
+        .collect();
+    for key in image_cache::invalidate(level_id).await {
+        if let Some((variant, format)) = Variant::from_cache_key(&key) {
+            for path in variant.purge_paths(level_id, format) {
+                if !extra_paths.contains(&path) {
+                    extra_paths.push(path);
+                }
+            }
+        }
+    }
+
+    cache_controller::purge(level_id, extra_paths);
+}
+
+fn image_response_builder(
+    id: u64,
+    upload_info: &database::UploadInfo,
+    format: Format,
+    etag: &str,
+) -> response::Builder {
+    let mut builder = Response::builder()
+        .header(header::CONTENT_TYPE, format.mime_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("inline; filename=\"{}.{}\"", id, format.extension()),
+        )
+        .header(header::CACHE_CONTROL, IMAGE_CACHE_CONTROL)
+        .header(header::VARY, "Accept")
+        .header(header::ETAG, etag)
+        .header("Cache-Tag", cache_controller::level_tag(id as i64))
         .header("X-Level-ID", id.to_string())
         .header("X-Thumbnail-Author", &upload_info.username)
-        .header("X-Thumbnail-User-ID", upload_info.account_id.to_string())
+        .header("X-Thumbnail-User-ID", upload_info.account_id.to_string());
+
+    if let Some(modified_time) = upload_info.modified_time {
+        builder = builder.header(header::LAST_MODIFIED, util::http_date(modified_time));
+    }
+
+    builder
+}
+
+fn image_response(
+    image_data: Vec<u8>,
+    id: u64,
+    upload_info: &database::UploadInfo,
+    format: Format,
+    etag: &str,
+) -> Response {
+    image_response_builder(id, upload_info, format, etag)
+        .header(header::CONTENT_LENGTH, image_data.len())
         .body(image_data.into())
         .unwrap()
 }
 
+// Streams the original file from disk, letting ServeFile take care of HEAD and Range requests
+async fn stream_original_image(
+    image_path: PathBuf,
+    id: u64,
+    upload_info: &database::UploadInfo,
+    etag: &str,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Response {
+    // Preconditions were already evaluated against the upload, only forward the range.
+    // A stale If-Range means the client's partial copy is outdated, so send the whole file.
+    let mut request = Request::new(Body::empty());
+    *request.method_mut() = method.clone();
+    if let Some(range) = headers.get(header::RANGE)
+        && headers.get(header::IF_RANGE).is_none_or(|tag| tag.as_bytes() == etag.as_bytes())
+    {
+        request.headers_mut().insert(header::RANGE, range.clone());
+    }
+
+    let file_response = match ServeFile::new(image_path).oneshot(request).await {
+        Ok(response) => response,
+        Err(e) => match e {},
+    };
+
+    let (mut parts, body) = file_response.into_parts();
+    if parts.status.is_success() {
+        let builder = image_response_builder(id, upload_info, Format::Webp, etag);
+        if let Some(image_headers) = builder.headers_ref() {
+            for (name, value) in image_headers {
+                parts.headers.insert(name, value.clone());
+            }
+        }
+    }
+
+    Response::from_parts(parts, Body::new(body))
+}
+
 async fn get_upload_info(
     db: &database::AppState,
     id: u64,
@@ -60,43 +533,35 @@ async fn get_upload_info(
     }
 }
 
-async fn read_original_image(image_path: &PathBuf) -> Result<Vec<u8>, Response> {
-    tokio::fs::read(image_path).await.map_err(|e| {
-        util::str_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            &format!("Failed to read image file: {}", e),
-        )
-    })
-}
-
-async fn resize_image(image_path: PathBuf, target_res: Res) -> Result<Vec<u8>, Response> {
-    let (width, height) = target_res.dimensions();
-
-    tokio::task::spawn_blocking(move || -> Result<Vec<u8>, String> {
+async fn render_image(
+    image_path: PathBuf,
+    variant: Variant,
+    format: Format,
+    profile: EncodingProfile,
+    focal_point: Option<(f32, f32)>,
+) -> Result<Vec<u8>, RenderError> {
+    render_pool::run(move || {
         let image = ImageReader::open(&image_path)
             .map_err(|e| format!("Failed to open image: {}", e))?
             .decode()
             .map_err(|e| format!("Failed to decode image: {}", e))?;
 
-        let resized_image =
-            image.resize_exact(width, height, image::imageops::FilterType::Lanczos3).to_rgb8();
-
-        Ok(Encoder::from_rgb(&resized_image, width, height).encode_lossless().to_vec())
+        format
+            .encode(&variant.apply(image, focal_point), &profile)
+            .map_err(|e| format!("Image processing error: {}", e))
     })
     .await
-    .map_err(|e| {
-        util::str_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("Task join error: {}", e))
-    })?
-    .map_err(|e| {
-        util::str_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            &format!("Image processing error: {}", e),
-        )
-    })
 }
 
-async fn handle_image(id: u64, res: Res, db: database::AppState) -> Response {
-    // info!("Handling image request for ID: {}, Resolution: {:?}", id, res);
+async fn handle_image(
+    id: u64,
+    variant: Variant,
+    format: Format,
+    method: &Method,
+    headers: &HeaderMap,
+    db: database::AppState,
+) -> Response {
+    // info!("Handling image request for ID: {}, Variant: {:?}", id, variant);
 
     // Check if image file exists
     let image_path = PathBuf::from(format!("thumbnails/{}.webp", id));
@@ -110,94 +575,716 @@ async fn handle_image(id: u64, res: Res, db: database::AppState) -> Response {
         Err(response) => return response,
     };
 
-    match res {
-        Res::High => {
-            // For high resolution, serve the original image
-            let image_data = match read_original_image(&image_path).await {
-                Ok(data) => data,
-                Err(response) => return response,
-            };
-
-            image_response(image_data, id, &upload_info)
+    // Every accepted upload gets a new id, so it identifies the image content,
+    // along with the focal point for cropped variants
+    let cache_key = variant.cache_key(format);
+    let focal_point = upload_info.focal_point();
+    let etag = match focal_point {
+        Some((x, y)) if variant.uses_focal_point() => {
+            format!("\"{}-{}-{:.3}x{:.3}\"", upload_info.id, cache_key, x, y)
         }
+        _ => format!("\"{}-{}\"", upload_info.id, cache_key),
+    };
+    if util::is_not_modified(headers, &etag, upload_info.modified_time) {
+        let mut response =
+            util::not_modified(&etag, upload_info.modified_time, IMAGE_CACHE_CONTROL);
+        response.headers_mut().insert(header::VARY, header::HeaderValue::from_static("Accept"));
+        return response;
+    }
 
-        Res::Medium | Res::Small => {
-            // For lower resolutions, resize the image
-            let resized_data = match resize_image(image_path, res).await {
-                Ok(data) => data,
-                Err(response) => return response,
-            };
+    if variant == Variant::Res(Res::High) && format == Format::Webp {
+        // For high resolution WebP, serve the original image
+        return stream_original_image(image_path, id, &upload_info, &etag, method, headers).await;
+    }
 
-            image_response(resized_data, id, &upload_info)
+    // For everything else, serve the cached variant or render it once. Requests for the same
+    // variant that arrive while it is being rendered wait for that render instead of starting
+    // their own.
+    let profile = variant.encoding_profile(&db.settings.read().await.encoding);
+    let image_data = match image_cache::read(id as i64, upload_info.id, &cache_key).await {
+        Some(data) => data,
+        None => {
+            let rendered = render_pool::render_once(format!("{}:{}", id, etag), || async {
+                let data = render_image(image_path, variant, format, profile, focal_point).await?;
+                image_cache::store(id as i64, upload_info.id, &cache_key, &data).await;
+                Ok(data)
+            })
+            .await;
+
+            match rendered {
+                Ok(data) => data,
+                Err(e) => return e.into_response(),
+            }
         }
-    }
+    };
+
+    image_response(image_data, id, &upload_info, format, &etag)
 }
 
 pub async fn image_handler_with_res(
     Path((id, res)): Path<(u64, Res)>,
+    Query(query): Query<FormatQuery>,
+    method: Method,
+    headers: HeaderMap,
     State(db): State<database::AppState>,
 ) -> Response {
-    handle_image(id, res, db).await
+    let format = Format::negotiate(query.format, &headers);
+    handle_image(id, Variant::Res(res), format, &method, &headers, db).await
 }
 
 pub async fn image_handler_default(
     Path(id): Path<u64>,
+    Query(query): Query<SizeQuery>,
+    RawQuery(raw_query): RawQuery,
+    method: Method,
+    headers: HeaderMap,
     State(db): State<database::AppState>,
 ) -> Response {
-    handle_image(id, Res::High, db).await
+    let format = Format::negotiate(query.format, &headers);
+    let Some(size) = CustomSize::from_query(&query) else {
+        return handle_image(id, Variant::Res(Res::High), format, &method, &headers, db).await;
+    };
+
+    // Redirect to the snapped size, so the CDN only ever caches canonical URLs
+    let mut canonical_query = size.query_string();
+    if let Some(format) = query.format {
+        canonical_query.push_str(&format!("&format={}", format));
+    }
+
+    if raw_query.as_deref() != Some(canonical_query.as_str()) {
+        return Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, format!("/thumbnail/{}?{}", id, canonical_query))
+            .header(header::CACHE_CONTROL, "public, max-age=86400")
+            .body("".into())
+            .unwrap();
+    }
+
+    handle_image(id, Variant::Custom(size), format, &method, &headers, db).await
+}
+
+pub async fn blur_handler(
+    Path(id): Path<u64>,
+    Query(query): Query<BlurQuery>,
+    RawQuery(raw_query): RawQuery,
+    method: Method,
+    headers: HeaderMap,
+    State(db): State<database::AppState>,
+) -> Response {
+    let format = Format::negotiate(query.format, &headers);
+    let params = BlurParams::from_query(&query);
+
+    // Same as custom sizes, explicit parameters are redirected to their snapped values
+    if query.radius.is_some() || query.brightness.is_some() {
+        let mut canonical_query = params.query_string();
+        if let Some(format) = query.format {
+            canonical_query.push_str(&format!("&format={}", format));
+        }
+
+        if raw_query.as_deref() != Some(canonical_query.as_str()) {
+            return Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, format!("/thumbnail/{}/blur?{}", id, canonical_query))
+                .header(header::CACHE_CONTROL, "public, max-age=86400")
+                .body("".into())
+                .unwrap();
+        }
+    }
+
+    handle_image(id, Variant::Blur(params), format, &method, &headers, db).await
 }
 
 pub async fn thumbnail_info_handler(
     Path(id): Path<u64>,
+    headers: HeaderMap,
+    State(db): State<database::AppState>,
+) -> Response {
+    let upload = match db.get_upload_extended(id as i64).await {
+        Some(upload) => upload,
+        None => return util::str_response(StatusCode::NOT_FOUND, "Image not found"),
+    };
+
+    // Usernames can change without a new upload, so the ETag is taken from the body itself
+    let body = serde_json::to_string(&upload).unwrap();
+    let etag = util::content_etag(body.as_bytes());
+    let modified_time = upload.accepted_time.max(upload.restored_time);
+    if util::is_not_modified(&headers, &etag, modified_time) {
+        return util::not_modified(&etag, modified_time, INFO_CACHE_CONTROL);
+    }
+
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, INFO_CACHE_CONTROL)
+        .header(header::ETAG, &etag);
+
+    if let Some(modified_time) = modified_time {
+        builder = builder.header(header::LAST_MODIFIED, util::http_date(modified_time));
+    }
+
+    builder.body(body.into()).unwrap()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BatchQuery {
+    ids: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BatchPayload {
+    ids: Vec<i64>,
+}
+
+// Drops duplicate ids and enforces the batch size limit
+fn validate_level_ids(mut level_ids: Vec<i64>) -> Result<Vec<i64>, String> {
+    let mut seen = HashSet::with_capacity(level_ids.len());
+    level_ids.retain(|id| seen.insert(*id));
+
+    if level_ids.is_empty() {
+        return Err("No level IDs provided".to_string());
+    }
+
+    if level_ids.len() > MAX_BATCH_SIZE {
+        return Err(format!("At most {} level IDs can be requested at once", MAX_BATCH_SIZE));
+    }
+
+    Ok(level_ids)
+}
+
+// Parses a comma separated list of level ids
+fn parse_level_ids(ids: &str) -> Result<Vec<i64>, String> {
+    let ids = ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse::<i64>().map_err(|_| format!("Invalid level ID: {}", id)))
+        .collect::<Result<Vec<i64>, String>>()?;
+
+    validate_level_ids(ids)
+}
+
+pub async fn placeholders_handler(
+    Query(query): Query<BatchQuery>,
     State(db): State<database::AppState>,
 ) -> Response {
-    match db.get_upload_extended(id as i64).await {
-        Some(upload) => Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "application/json")
-            .header(header::CACHE_CONTROL, "no-store")
-            .body(serde_json::to_string(&upload).unwrap().into())
-            .unwrap(),
-        None => util::str_response(StatusCode::NOT_FOUND, "Image not found"),
+    let level_ids = match parse_level_ids(&query.ids) {
+        Ok(ids) => ids,
+        Err(e) => return util::str_response(StatusCode::BAD_REQUEST, &e),
+    };
+
+    match db.get_placeholders(&level_ids).await {
+        Ok(rows) => {
+            let missing: Vec<i64> = level_ids
+                .iter()
+                .copied()
+                .filter(|id| !rows.iter().any(|row| row.level_id == *id))
+                .collect();
+            let placeholders: serde_json::Map<String, serde_json::Value> = rows
+                .into_iter()
+                .map(|row| (row.level_id.to_string(), row.placeholder.into()))
+                .collect();
+
+            util::response(
+                StatusCode::OK,
+                serde_json::json!({
+                    "placeholders": placeholders,
+                    "missing": missing,
+                }),
+            )
+        }
+        Err(e) => util::str_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Failed to get placeholders: {}", e),
+        ),
     }
 }
 
-pub async fn handle_random(res: Res) -> Response {
-    // pick random id from directory
-    match tokio::fs::read_dir("thumbnails").await {
-        Ok(mut entries) => {
-            let mut ids: Vec<u64> = Vec::new();
-            while let Some(entry) = entries.next_entry().await.unwrap() {
-                if let Some(name) = entry.file_name().to_str() {
-                    if let Ok(id) = name.trim_end_matches(".webp").parse::<u64>() {
-                        ids.push(id);
-                    }
-                }
-            }
+async fn handle_batch_info(level_ids: Vec<i64>, db: database::AppState) -> Response {
+    match db.get_upload_extended_batch(&level_ids).await {
+        Ok(uploads) => {
+            let missing: Vec<i64> = level_ids
+                .iter()
+                .copied()
+                .filter(|id| !uploads.iter().any(|upload| upload.level_id == *id))
+                .collect();
+            let thumbnails: serde_json::Map<String, serde_json::Value> = uploads
+                .into_iter()
+                .map(|upload| (upload.level_id.to_string(), serde_json::to_value(upload).unwrap()))
+                .collect();
+
+            util::response(
+                StatusCode::OK,
+                serde_json::json!({
+                    "thumbnails": thumbnails,
+                    "missing": missing,
+                }),
+            )
+        }
+        Err(e) => util::str_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Failed to get thumbnail info: {}", e),
+        ),
+    }
+}
+
+pub async fn batch_info_handler(
+    Query(query): Query<BatchQuery>,
+    State(db): State<database::AppState>,
+) -> Response {
+    match parse_level_ids(&query.ids) {
+        Ok(level_ids) => handle_batch_info(level_ids, db).await,
+        Err(e) => util::str_response(StatusCode::BAD_REQUEST, &e),
+    }
+}
 
-            if ids.is_empty() {
-                return util::str_response(StatusCode::NOT_FOUND, "No images found");
+pub async fn batch_info_post_handler(
+    State(db): State<database::AppState>,
+    Json(payload): Json<BatchPayload>,
+) -> Response {
+    match validate_level_ids(payload.ids) {
+        Ok(level_ids) => handle_batch_info(level_ids, db).await,
+        Err(e) => util::str_response(StatusCode::BAD_REQUEST, &e),
+    }
+}
+
+async fn handle_exists(level_ids: Vec<i64>, db: database::AppState) -> Response {
+    match db.get_existing_level_ids(&level_ids).await {
+        Ok(existing) => {
+            let (exists, missing): (Vec<i64>, Vec<i64>) =
+                level_ids.into_iter().partition(|id| existing.contains(id));
+            util::response(
+                StatusCode::OK,
+                serde_json::json!({
+                    "exists": exists,
+                    "missing": missing,
+                }),
+            )
+        }
+        Err(e) => util::str_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Failed to check thumbnails: {}", e),
+        ),
+    }
+}
+
+pub async fn exists_handler(
+    Query(query): Query<BatchQuery>,
+    State(db): State<database::AppState>,
+) -> Response {
+    match parse_level_ids(&query.ids) {
+        Ok(level_ids) => handle_exists(level_ids, db).await,
+        Err(e) => util::str_response(StatusCode::BAD_REQUEST, &e),
+    }
+}
+
+pub async fn exists_post_handler(
+    State(db): State<database::AppState>,
+    Json(payload): Json<BatchPayload>,
+) -> Response {
+    match validate_level_ids(payload.ids) {
+        Ok(level_ids) => handle_exists(level_ids, db).await,
+        Err(e) => util::str_response(StatusCode::BAD_REQUEST, &e),
+    }
+}
+
+struct SerializedBloom {
+    generation: u64,
+    data: Bytes,
+    etag: String,
+    bit_count: u64,
+    hash_count: u32,
+    items: usize,
+}
+
+// The last filter that was built, reused until a level gains or loses its thumbnail
+static BLOOM: LazyLock<Mutex<Option<Arc<SerializedBloom>>>> = LazyLock::new(Default::default);
+
+fn current_bloom(index: &ThumbnailIndex) -> Arc<SerializedBloom> {
+    if let Some(bloom) = BLOOM.lock().unwrap().as_ref()
+        && bloom.generation == index.generation()
+    {
+        return bloom.clone();
+    }
+
+    let (level_ids, generation) = index.ids_with_generation();
+    let mut filter = BloomFilter::with_capacity(level_ids.len());
+    for id in &level_ids {
+        filter.insert(*id);
+    }
+
+    let (bit_count, hash_count) = (filter.bit_count(), filter.hash_count());
+    let data = Bytes::from(filter.into_bytes());
+    let bloom = Arc::new(SerializedBloom {
+        generation,
+        etag: util::content_etag(&data),
+        data,
+        bit_count,
+        hash_count,
+        items: level_ids.len(),
+    });
+
+    *BLOOM.lock().unwrap() = Some(bloom.clone());
+    bloom
+}
+
+// Bloom filter of every level with a thumbnail, see bloom.rs for how to query it
+pub async fn bloom_handler(headers: HeaderMap, State(db): State<database::AppState>) -> Response {
+    let bloom = current_bloom(&db.index);
+    if util::is_not_modified(&headers, &bloom.etag, None) {
+        return util::not_modified(&bloom.etag, None, BLOOM_CACHE_CONTROL);
+    }
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CACHE_CONTROL, BLOOM_CACHE_CONTROL)
+        .header(header::CONTENT_LENGTH, bloom.data.len())
+        .header(header::ETAG, &bloom.etag)
+        .header("X-Bloom-Bits", bloom.bit_count.to_string())
+        .header("X-Bloom-Hashes", bloom.hash_count.to_string())
+        .header("X-Bloom-Items", bloom.items.to_string())
+        .body(bloom.data.clone().into())
+        .unwrap()
+}
+
+pub async fn history_handler(
+    Path(id): Path<u64>,
+    State(db): State<database::AppState>,
+) -> Response {
+    let history =
+        tokio::try_join!(db.get_thumbnail_history(id as i64), db.get_thumbnail_reverts(id as i64));
+
+    match history {
+        Ok((versions, _)) if versions.is_empty() => {
+            util::str_response(StatusCode::NOT_FOUND, "Image not found")
+        }
+        Ok((mut versions, reverts)) => {
+            for version in versions.iter_mut() {
+                // versions accepted before history was kept only exist if they are current
+                version.available = version.current
+                    || tokio::fs::try_exists(history::version_path(id as i64, version.id))
+                        .await
+                        .unwrap_or(false);
             }
 
-            let random_id = ids[rand::random::<u64>() as usize % ids.len()];
-            let url = format!("/thumbnail/{}/{}", random_id, res.to_string());
-            Response::builder()
-                .status(StatusCode::FOUND)
-                .header(header::LOCATION, url)
-                .body("".into())
-                .unwrap()
+            util::response(
+                StatusCode::OK,
+                serde_json::json!({
+                    "level_id": id,
+                    "versions": versions,
+                    "reverts": reverts,
+                }),
+            )
         }
         Err(e) => util::str_response(
             StatusCode::INTERNAL_SERVER_ERROR,
-            &format!("Failed to get thumbnails: {}", e),
+            &format!("Failed to get thumbnail history: {}", e),
         ),
     }
 }
 
-pub async fn random_handler() -> Response {
-    handle_random(Res::High).await
+pub async fn version_handler(
+    Path((id, upload_id)): Path<(u64, i64)>,
+    method: Method,
+    headers: HeaderMap,
+    State(db): State<database::AppState>,
+) -> Response {
+    let Some(upload_info) = db.get_upload_version(id as i64, upload_id).await else {
+        return util::str_response(StatusCode::NOT_FOUND, "Version not found");
+    };
+
+    let etag = format!("\"{}-{}\"", upload_id, Variant::Res(Res::High).cache_key(Format::Webp));
+    if util::is_not_modified(&headers, &etag, upload_info.modified_time) {
+        return util::not_modified(&etag, upload_info.modified_time, IMAGE_CACHE_CONTROL);
+    }
+
+    let mut image_path = history::version_path(id as i64, upload_id);
+    if !image_path.exists() {
+        let is_current =
+            db.get_upload_info(id as i64).await.is_some_and(|info| info.id == upload_id);
+        if !is_current {
+            return util::str_response(
+                StatusCode::NOT_FOUND,
+                "This version is no longer available",
+            );
+        }
+        image_path = history::current_path(id as i64);
+    }
+
+    stream_original_image(image_path, id, &upload_info, &etag, &method, &headers).await
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct RecentQuery {
+    cursor: Option<String>,
+    limit: Option<u32>,
+    replacement_only: bool,
+    new_only: bool,
+}
+
+// Cursors are "{accepted time in microseconds}_{upload id}" of the last item on the page
+fn encode_cursor(accepted_time: NaiveDateTime, id: i64) -> String {
+    format!("{}_{}", accepted_time.and_utc().timestamp_micros(), id)
+}
+
+fn decode_cursor(cursor: &str) -> Option<(NaiveDateTime, i64)> {
+    let (micros, id) = cursor.split_once('_')?;
+    let accepted_time = DateTime::from_timestamp_micros(micros.parse().ok()?)?.naive_utc();
+    Some((accepted_time, id.parse().ok()?))
+}
+
+pub async fn recent_handler(
+    Query(query): Query<RecentQuery>,
+    State(db): State<database::AppState>,
+) -> Response {
+    let before = match query.cursor.as_deref().map(decode_cursor) {
+        Some(None) => return util::str_response(StatusCode::BAD_REQUEST, "Invalid cursor"),
+        Some(cursor) => cursor,
+        None => None,
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_RECENT_LIMIT).clamp(1, MAX_RECENT_LIMIT);
+    let options = database::RecentQueryOptions {
+        before,
+        // one extra row tells whether there is another page
+        limit: limit + 1,
+        replacement_only: query.replacement_only,
+        new_only: query.new_only,
+    };
+
+    match db.get_recent_thumbnails(&options).await {
+        Ok(mut thumbnails) => {
+            let next_cursor = if thumbnails.len() > limit as usize {
+                thumbnails.truncate(limit as usize);
+                thumbnails.last().map(|last| encode_cursor(last.accepted_time, last.id))
+            } else {
+                None
+            };
+
+            util::response(
+                StatusCode::OK,
+                serde_json::json!({
+                    "thumbnails": thumbnails,
+                    "next_cursor": next_cursor,
+                }),
+            )
+        }
+        Err(e) => util::str_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Failed to get recent thumbnails: {}", e),
+        ),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RandomQuery {
+    uploader: Option<String>,
+    accepted_after: Option<NaiveDate>,
+    exclude: Option<String>,
+    seed: Option<String>,
+}
+
+// SplitMix64 over an FNV-1a hash of the seed, so any string can be used as a seed
+fn seeded_index(seed: &str, len: usize) -> usize {
+    let mut x = seed
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+        .wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+    (x % len as u64) as usize
+}
+
+async fn pick_random(
+    query: &RandomQuery,
+    seed: Option<&str>,
+    filter: database::RandomFilter,
+    db: &database::AppState,
+) -> Result<Option<i64>, Response> {
+    let exclude = match query.exclude.as_deref() {
+        Some(ids) => {
+            parse_level_ids(ids).map_err(|e| util::str_response(StatusCode::BAD_REQUEST, &e))?
+        }
+        None => Vec::new(),
+    };
+
+    let filtered = filter.uploader.is_some()
+        || filter.accepted_after.is_some()
+        || filter.first_accepted_before.is_some();
+    if !filtered && seed.is_none() && exclude.is_empty() {
+        return Ok(db.index.random());
+    }
+
+    let mut candidates = if filtered {
+        db.get_random_candidates(&filter).await.map_err(|e| {
+            util::str_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("Failed to get thumbnails: {}", e),
+            )
+        })?
+    } else {
+        db.index.sorted_ids()
+    };
+    candidates.retain(|id| !exclude.contains(id));
+
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+
+    let index = match seed {
+        Some(seed) => seeded_index(seed, candidates.len()),
+        None => rand::random::<u64>() as usize % candidates.len(),
+    };
+    Ok(Some(candidates[index]))
+}
+
+fn random_redirect(id: i64, res: Res, cache_control: &str) -> Response {
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, format!("/thumbnail/{}/{}", id, res))
+        .header(header::CACHE_CONTROL, cache_control)
+        .body("".into())
+        .unwrap()
 }
 
-pub async fn random_res_handler(Path(res): Path<Res>) -> Response {
-    handle_random(res).await
+pub async fn handle_random(res: Res, query: RandomQuery, db: &database::AppState) -> Response {
+    let filter = database::RandomFilter {
+        uploader: query.uploader.clone(),
+        accepted_after: query.accepted_after.map(|date| date.and_hms_opt(0, 0, 0).unwrap()),
+        first_accepted_before: None,
+    };
+
+    match pick_random(&query, query.seed.as_deref(), filter, db).await {
+        Ok(Some(id)) => random_redirect(id, res, "no-store"),
+        Ok(None) => util::str_response(StatusCode::NOT_FOUND, "No images found"),
+        Err(resp) => resp,
+    }
+}
+
+pub async fn random_handler(
+    Query(query): Query<RandomQuery>,
+    State(db): State<database::AppState>,
+) -> Response {
+    handle_random(Res::High, query, &db).await
+}
+
+pub async fn random_res_handler(
+    Path(res): Path<Res>,
+    Query(query): Query<RandomQuery>,
+    State(db): State<database::AppState>,
+) -> Response {
+    handle_random(res, query, &db).await
+}
+
+// Seeded with the current UTC date. Only levels that already had a thumbnail before the day
+// started are considered, so new uploads and replacements don't change the pick halfway
+// through the day.
+pub async fn handle_daily(res: Res, query: RandomQuery, db: &database::AppState) -> Response {
+    let now = Utc::now().naive_utc();
+    let today = now.date().and_hms_opt(0, 0, 0).unwrap();
+    let tomorrow = today + chrono::Duration::days(1);
+
+    let filter = database::RandomFilter {
+        uploader: query.uploader.clone(),
+        accepted_after: query.accepted_after.map(|date| date.and_hms_opt(0, 0, 0).unwrap()),
+        first_accepted_before: Some(today),
+    };
+
+    let seed = format!("daily-{}", today.date());
+    match pick_random(&query, Some(&seed), filter, db).await {
+        Ok(Some(id)) => {
+            let cache_control = format!("public, max-age={}", (tomorrow - now).num_seconds());
+            random_redirect(id, res, &cache_control)
+        }
+        Ok(None) => util::str_response(StatusCode::NOT_FOUND, "No images found"),
+        Err(resp) => resp,
+    }
+}
+
+pub async fn daily_handler(
+    Query(query): Query<RandomQuery>,
+    State(db): State<database::AppState>,
+) -> Response {
+    handle_daily(Res::High, query, &db).await
+}
+
+pub async fn daily_res_handler(
+    Path(res): Path<Res>,
+    Query(query): Query<RandomQuery>,
+    State(db): State<database::AppState>,
+) -> Response {
+    handle_daily(res, query, &db).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size_query(w: Option<u32>, h: Option<u32>) -> SizeQuery {
+        SizeQuery { w, h, ..Default::default() }
+    }
+
+    #[test]
+    fn snap_size_rounds_up_to_allowed_sizes() {
+        assert_eq!(snap_size(0, MAX_WIDTH), 32);
+        assert_eq!(snap_size(32, MAX_WIDTH), 32);
+        assert_eq!(snap_size(33, MAX_WIDTH), 64);
+        assert_eq!(snap_size(1000, MAX_WIDTH), 1080);
+    }
+
+    #[test]
+    fn snap_size_clamps_to_max() {
+        assert_eq!(snap_size(5000, MAX_WIDTH), 1920);
+        assert_eq!(snap_size(1200, MAX_HEIGHT), 1080);
+        assert_eq!(snap_size(1081, MAX_HEIGHT), 1080);
+    }
+
+    #[test]
+    fn custom_size_from_query() {
+        assert_eq!(CustomSize::from_query(&size_query(None, None)), None);
+
+        let size = CustomSize::from_query(&size_query(Some(500), Some(300))).unwrap();
+        assert_eq!((size.width, size.height, size.fit), (540, 300, Fit::Cover));
+
+        let size = CustomSize::from_query(&size_query(Some(9999), Some(9999))).unwrap();
+        assert_eq!((size.width, size.height), (1920, 1080));
+    }
+
+    #[test]
+    fn custom_size_derives_missing_dimension() {
+        let size = CustomSize::from_query(&size_query(Some(600), None)).unwrap();
+        assert_eq!((size.width, size.height), (640, 360));
+
+        let size = CustomSize::from_query(&size_query(None, Some(100))).unwrap();
+        assert_eq!((size.width, size.height), (177, 100));
+    }
+
+    #[test]
+    fn custom_size_cache_key_round_trip() {
+        let size = CustomSize {
+            width: 640,
+            height: 360,
+            fit: Fit::Contain,
+        };
+        assert_eq!(CustomSize::from_cache_key(&size.cache_key()), Some(size));
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let accepted_time = DateTime::from_timestamp_micros(1_760_700_000_123_456).unwrap();
+        let cursor = encode_cursor(accepted_time.naive_utc(), 42);
+        assert_eq!(cursor, "1760700000123456_42");
+        assert_eq!(decode_cursor(&cursor), Some((accepted_time.naive_utc(), 42)));
+    }
+
+    #[test]
+    fn cursor_rejects_bad_input() {
+        assert_eq!(decode_cursor(""), None);
+        assert_eq!(decode_cursor("1760700000123456"), None);
+        assert_eq!(decode_cursor("abc_42"), None);
+        assert_eq!(decode_cursor("1760700000123456_"), None);
+        assert_eq!(decode_cursor("1760700000123456_4_2"), None);
+        assert_eq!(decode_cursor(&format!("{}_42", i64::MAX)), None);
+    }
 }
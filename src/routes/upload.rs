@@ -1,72 +1,331 @@
-use crate::{cache_controller, database, util};
+use crate::encoding::EncodingProfile;
+use crate::render_pool;
+use crate::routes::{admin, thumbnail};
+use crate::{blurhash, database, dhash, history, palette, quality, util};
 use axum::Json;
 use axum::body::Bytes;
+use axum::extract::rejection::BytesRejection;
 use axum::extract::{Path, Query, State};
 use axum::http::{HeaderMap, StatusCode, header};
 use axum::response::Response;
+use image::{DynamicImage, ImageError, ImageFormat, ImageReader};
 use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
-use webp::Encoder;
+use std::io::Cursor;
+use std::path::PathBuf;
+use tracing::{error, info};
 
+// Every thumbnail is stored at this size, uploads are scaled to it
 const IMAGE_WIDTH: u32 = 1920;
 const IMAGE_HEIGHT: u32 = 1080;
+const MIN_UPLOAD_WIDTH: u32 = 1280;
+const MIN_UPLOAD_HEIGHT: u32 = 720;
+const MAX_UPLOAD_WIDTH: u32 = 7680;
+const MAX_UPLOAD_HEIGHT: u32 = 4320;
+// How far the aspect ratio may be off from 16:9 and still be scaled as is,
+// and how far it may be off when the uploader asks for it to be cropped
+const ASPECT_TOLERANCE: f32 = 0.01;
+const CROP_TOLERANCE: f32 = 0.35;
+// Request body limit unless MAX_UPLOAD_SIZE is set, the same as axum's default
+pub const DEFAULT_MAX_UPLOAD_SIZE: usize = 2 * 1024 * 1024;
+// Formats are detected from the file contents, the Content-Type header is ignored
+const ALLOWED_FORMATS: [ImageFormat; 3] = [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::WebP];
+// Enough for the largest allowed upload with 16-bit channels
+const MAX_DECODE_ALLOC: u64 = 320 * 1024 * 1024;
+// Uploads whose hashes differ in at most this many of the 64 bits are the same picture
+const DUPLICATE_DISTANCE: i64 = 6;
 const DEFAULT_PENDING_PAGE_SIZE: u32 = 24;
 const MAX_PENDING_PAGE_SIZE: u32 = 100;
 
-// Helper function to authenticate moderator/admin
-async fn authenticate_moderator(
-    headers: &HeaderMap,
-    db: &database::AppState,
-) -> Result<database::User, Response> {
-    let user = util::auth_middleware(headers, db).await?;
+#[derive(Debug)]
+struct UploadError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    matched_upload_id: Option<i64>,
+}
 
-    if !matches!(user.role, database::Role::Moderator | database::Role::Admin) {
-        return Err(util::str_response(
-            StatusCode::FORBIDDEN,
-            "Only moderators or admins can perform this action",
-        ));
+impl UploadError {
+    fn new(code: &'static str, message: String) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            code,
+            message,
+            matched_upload_id: None,
+        }
+    }
+
+    fn response(&self) -> Response {
+        match self.matched_upload_id {
+            Some(matched_upload_id) => util::error_response_with(
+                self.status,
+                self.code,
+                &self.message,
+                serde_json::json!({ "matched_upload_id": matched_upload_id }),
+            ),
+            None => util::error_response(self.status, self.code, &self.message),
+        }
     }
+}
 
-    Ok(user)
+// A validated upload, ready to be stored
+struct ProcessedImage {
+    data: Vec<u8>,
+    analysis: database::UploadAnalysis,
 }
 
-// Helper function to validate image dimensions and convert to WebP
-fn process_image(data: &[u8]) -> Result<Vec<u8>, String> {
-    let image = image::load_from_memory(data).map_err(|e| format!("Invalid image data: {}", e))?;
+pub fn max_upload_size() -> usize {
+    dotenv::var("MAX_UPLOAD_SIZE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_UPLOAD_SIZE)
+}
+
+// Decodes an upload without trusting its headers: unknown formats are refused before any
+// decoding happens, and the decoder stops as soon as the image would get too big
+fn decode_upload(data: &[u8]) -> Result<DynamicImage, UploadError> {
+    let format = image::guess_format(data).ok();
+    let Some(format) = format.filter(|format| ALLOWED_FORMATS.contains(format)) else {
+        return Err(UploadError {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            code: "unsupported_format",
+            message: "Only PNG, JPEG and WebP images are supported".to_string(),
+            matched_upload_id: None,
+        });
+    };
+
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(MAX_UPLOAD_WIDTH);
+    limits.max_image_height = Some(MAX_UPLOAD_HEIGHT);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(limits);
+    reader.decode().map_err(|e| match e {
+        ImageError::Limits(_) => UploadError::new(
+            "image_too_large",
+            format!("Image must be at most {}x{}", MAX_UPLOAD_WIDTH, MAX_UPLOAD_HEIGHT),
+        ),
+        e => UploadError::new("invalid_image", format!("Invalid image data: {}", e)),
+    })
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct UploadQuery {
+    crop: bool, // center-crop images that are close to, but not exactly 16:9
+}
+
+// Validates the image and scales it to the canonical size, converted to WebP.
+// Images that look broken are refused unless allow_broken is set, they are still flagged.
+fn process_image(
+    data: &[u8],
+    profile: &EncodingProfile,
+    crop: bool,
+    allow_broken: bool,
+) -> Result<ProcessedImage, UploadError> {
+    let mut image = decode_upload(data)?;
+
+    let (width, height) = (image.width(), image.height());
+    let target_ratio = IMAGE_WIDTH as f32 / IMAGE_HEIGHT as f32;
+    let deviation = (width as f32 / height as f32 / target_ratio - 1.0).abs();
+    if deviation > ASPECT_TOLERANCE {
+        if !crop || deviation > CROP_TOLERANCE {
+            return Err(UploadError::new(
+                "invalid_aspect_ratio",
+                format!("Image must have a 16:9 aspect ratio, got {}x{}", width, height),
+            ));
+        }
+
+        let crop_width = width.min((height as f32 * target_ratio).round() as u32);
+        let crop_height = height.min((width as f32 / target_ratio).round() as u32);
+        image = image.crop_imm(
+            (width - crop_width) / 2,
+            (height - crop_height) / 2,
+            crop_width,
+            crop_height,
+        );
+    }
+
+    if image.width() < MIN_UPLOAD_WIDTH || image.height() < MIN_UPLOAD_HEIGHT {
+        return Err(UploadError::new(
+            "image_too_small",
+            format!("Image must be at least {}x{}", MIN_UPLOAD_WIDTH, MIN_UPLOAD_HEIGHT),
+        ));
+    }
 
     if image.width() != IMAGE_WIDTH || image.height() != IMAGE_HEIGHT {
-        return Err(format!("Image must be exactly {}x{}", IMAGE_WIDTH, IMAGE_HEIGHT));
+        image =
+            image.resize_exact(IMAGE_WIDTH, IMAGE_HEIGHT, image::imageops::FilterType::Lanczos3);
+    }
+
+    let image = image.into_rgb8();
+    let report = quality::check(&image);
+    if report.is_broken() && !allow_broken {
+        return Err(UploadError::new(
+            "low_quality",
+            format!("Image looks blank or broken: {}", report.flags.join(", ")),
+        ));
     }
 
-    let rgb_data = image.into_rgb8();
-    let encoder = Encoder::from_rgb(&rgb_data, IMAGE_WIDTH, IMAGE_HEIGHT);
-    Ok(encoder.encode_lossless().to_owned())
+    let data = profile.encode(&image).map_err(|e| UploadError {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        code: "encoding_failed",
+        message: e,
+        matched_upload_id: None,
+    })?;
+
+    Ok(ProcessedImage {
+        data,
+        analysis: database::UploadAnalysis {
+            phash: dhash::hash(&image),
+            quality_flags: report.flags.iter().map(|flag| flag.to_string()).collect(),
+            quality_score: report.score,
+        },
+    })
+}
+
+// Rejects images that are already live for the level, or that were already rejected
+// when the same user submitted them before
+async fn check_duplicate(
+    db: &database::AppState,
+    level_id: i64,
+    user_id: i64,
+    phash: i64,
+) -> Result<(), UploadError> {
+    let duplicate = db
+        .find_duplicate_upload(level_id, user_id, phash, DUPLICATE_DISTANCE)
+        .await
+        .map_err(|e| UploadError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            code: "duplicate_check_failed",
+            message: format!("Failed to check for duplicate uploads: {}", e),
+            matched_upload_id: None,
+        })?;
+
+    match duplicate {
+        Some(duplicate) => Err(UploadError {
+            status: StatusCode::CONFLICT,
+            code: "duplicate_upload",
+            message: if duplicate.accepted {
+                "This image is already the thumbnail of this level".to_string()
+            } else {
+                "This image was already rejected for this level".to_string()
+            },
+            matched_upload_id: Some(duplicate.id),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Computes the data clients need before downloading an accepted thumbnail
+// and stores it with the upload. Failures are logged, but never fail the upload itself.
+async fn store_thumbnail_metadata(db: &database::AppState, upload_id: i64, image_path: PathBuf) {
+    let metadata = render_pool::run_waiting(move || {
+        let image = ImageReader::open(&image_path)
+            .map_err(|e| format!("Failed to open image: {}", e))?
+            .decode()
+            .map_err(|e| format!("Failed to decode image: {}", e))?
+            .into_rgb8();
+
+        let palette = palette::extract(&image);
+        Ok(database::ThumbnailMetadata {
+            placeholder: blurhash::encode(&image),
+            dominant_color: palette.first().cloned().unwrap_or_default(),
+            palette,
+            phash: dhash::hash(&image),
+        })
+    })
+    .await;
+
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!("Failed to analyze thumbnail for upload {}: {:?}", upload_id, e);
+            return;
+        }
+    };
+
+    if let Err(e) = db.set_thumbnail_metadata(upload_id, &metadata).await {
+        error!("Failed to store thumbnail metadata for upload {}: {}", upload_id, e);
+    }
+}
+
+// Computes the metadata of thumbnails that were accepted before it existed. Runs in the
+// background at startup, one thumbnail at a time so requests still get render workers.
+pub async fn backfill_thumbnail_metadata(db: database::AppState) {
+    let uploads = match db.get_uploads_missing_metadata().await {
+        Ok(uploads) => uploads,
+        Err(e) => {
+            error!("Failed to find thumbnails without metadata: {}", e);
+            return;
+        }
+    };
+
+    if uploads.is_empty() {
+        return;
+    }
+
+    info!("Computing metadata for {} thumbnails", uploads.len());
+    for (level_id, upload_id) in &uploads {
+        store_thumbnail_metadata(&db, *upload_id, history::current_path(*level_id)).await;
+    }
+    info!("Computed metadata for {} thumbnails", uploads.len());
+}
+
+// Runs once an upload has become the current thumbnail of a level
+async fn on_thumbnail_accepted(db: &database::AppState, level_id: i64, upload_id: i64) {
+    if let Err(e) = history::archive(level_id, upload_id).await {
+        error!("Failed to archive upload {} of level {}: {}", upload_id, level_id, e);
+    }
+
+    db.index.refresh(level_id).await;
+    thumbnail::invalidate(level_id, &[]).await;
+
+    // Can take a while behind a busy render queue, so it doesn't hold up the response.
+    // If it fails, the startup backfill picks the thumbnail up again.
+    let db = db.clone();
+    tokio::spawn(async move {
+        store_thumbnail_metadata(&db, upload_id, history::current_path(level_id)).await;
+    });
+}
+
+// Keeps the file of the current thumbnail of a level before a new one is written over it
+async fn archive_outgoing(db: &database::AppState, level_id: i64) -> Result<(), String> {
+    match db.get_upload_info(level_id).await {
+        Some(current) => history::archive_current(level_id, current.id)
+            .await
+            .map_err(|e| format!("Failed to archive the current thumbnail: {}", e)),
+        None => Ok(()),
+    }
 }
 
 // Handler for uploading images for admins/moderators (and verified for new thumbnails)
 async fn force_save(
     id: u64,
-    image_data: &[u8],
+    image: &ProcessedImage,
     user: &database::User,
     db: &database::AppState,
 ) -> Result<(), String> {
     let image_path = format!("thumbnails/{}.webp", id);
 
-    tokio::fs::write(&image_path, image_data)
+    archive_outgoing(db, id as i64).await?;
+    history::replace_current(id as i64, &image.data)
         .await
         .map_err(|e| format!("Failed to save image: {}", e))?;
 
-    db.add_upload(id as i64, user.id, &image_path, true)
+    let upload_id = db
+        .add_upload(id as i64, user.id, &image_path, true, &image.analysis)
         .await
         .map_err(|e| format!("Failed to add upload entry: {}", e))?;
 
-    cache_controller::purge(id as i64);
+    on_thumbnail_accepted(db, id as i64, upload_id).await;
     Ok(())
 }
 
 async fn add_to_pending(
     id: u64,
-    image_data: &[u8],
+    image: &ProcessedImage,
     user: &database::User,
     db: &database::AppState,
 ) -> Response {
@@ -79,7 +338,7 @@ async fn add_to_pending(
 
     let image_path = format!("uploads/{}_{}.webp", user.id, id);
 
-    match tokio::fs::write(&image_path, image_data).await {
+    match tokio::fs::write(&image_path, &image.data).await {
         Ok(_) => {}
         Err(e) => {
             return util::str_response(
@@ -89,7 +348,7 @@ async fn add_to_pending(
         }
     }
 
-    match db.add_upload(id as i64, user.id, &image_path, false).await {
+    match db.add_upload(id as i64, user.id, &image_path, false, &image.analysis).await {
         Ok(_) => util::str_response(
             StatusCode::ACCEPTED,
             &format!("Image for level ID {} is now pending", id),
@@ -106,16 +365,12 @@ async fn has_pending_upload(user_id: i64, level_id: u64) -> bool {
     tokio::fs::try_exists(&image_path).await.unwrap_or(false)
 }
 
-async fn is_image_uploaded(id: u64) -> bool {
-    let image_path = format!("thumbnails/{}.webp", id);
-    tokio::fs::try_exists(&image_path).await.unwrap_or(false)
-}
-
 pub async fn upload(
     State(db): State<database::AppState>,
     headers: HeaderMap,
     Path(id): Path<u64>,
-    data: Bytes,
+    Query(query): Query<UploadQuery>,
+    data: Result<Bytes, BytesRejection>,
 ) -> Response {
     let user = match util::auth_middleware(&headers, &db).await {
         Ok(user) => user,
@@ -123,25 +378,52 @@ pub async fn upload(
     };
 
     // Check for existing pending uploads for regular and verified users
-    if matches!(user.role, database::Role::User | database::Role::Verified) {
-        if has_pending_upload(user.id, id).await {
-            return util::str_response(
-                StatusCode::CONFLICT,
-                &format!("You already have a pending thumbnail for level ID {}", id),
-            );
-        }
+    if matches!(user.role, database::Role::User | database::Role::Verified)
+        && has_pending_upload(user.id, id).await
+    {
+        return util::str_response(
+            StatusCode::CONFLICT,
+            &format!("You already have a pending thumbnail for level ID {}", id),
+        );
     }
 
-    // Process and validate the image
-    let webp_data = match process_image(&data) {
+    let data = match data {
         Ok(data) => data,
-        Err(e) => return util::str_response(StatusCode::BAD_REQUEST, &e),
+        Err(BytesRejection::FailedToBufferBody(e))
+            if e.status() == StatusCode::PAYLOAD_TOO_LARGE =>
+        {
+            return util::error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "file_too_large",
+                &format!("Uploads must be at most {} bytes", max_upload_size()),
+            );
+        }
+        Err(e) => return util::error_response(e.status(), "invalid_body", &e.body_text()),
     };
 
+    // Process and validate the image. Moderators and admins save directly and get to decide
+    // for themselves whether an image that looks broken is what they want.
+    let profile = db.settings.read().await.encoding.high;
+    let allow_broken = matches!(user.role, database::Role::Admin | database::Role::Moderator);
+    let image = match render_pool::run(move || {
+        Ok(process_image(&data, &profile, query.crop, allow_broken))
+    })
+    .await
+    {
+        Ok(Ok(image)) => image,
+        Ok(Err(e)) => return e.response(),
+        // a full queue is 503, a job that failed or panicked is on us and 500
+        Err(e) => return e.into_response(),
+    };
+
+    if let Err(e) = check_duplicate(&db, id as i64, user.id, image.analysis.phash).await {
+        return e.response();
+    }
+
     match user.role {
         // Admins and moderators can upload and replace images directly
         database::Role::Admin | database::Role::Moderator => {
-            match force_save(id, &webp_data, &user, &db).await {
+            match force_save(id, &image, &user, &db).await {
                 Ok(_) => util::str_response(
                     StatusCode::CREATED,
                     &format!("Image for level ID {} uploaded", id),
@@ -155,8 +437,8 @@ pub async fn upload(
 
         // Verified users can upload new images directly, but replacements need approval
         database::Role::Verified => {
-            if !is_image_uploaded(id).await {
-                match force_save(id, &webp_data, &user, &db).await {
+            if !db.index.contains(id as i64) {
+                match force_save(id, &image, &user, &db).await {
                     Ok(_) => util::str_response(
                         StatusCode::CREATED,
                         &format!("Image for level ID {} uploaded", id),
@@ -168,12 +450,12 @@ pub async fn upload(
                 }
             } else {
                 // Image exists, add to pending for approval
-                add_to_pending(id, &webp_data, &user, &db).await
+                add_to_pending(id, &image, &user, &db).await
             }
         }
 
         // Regular users must go through approval process
-        database::Role::User => add_to_pending(id, &webp_data, &user, &db).await,
+        database::Role::User => add_to_pending(id, &image, &user, &db).await,
     }
 }
 
@@ -225,6 +507,13 @@ impl PendingQueryParams {
     }
 }
 
+#[derive(Serialize)]
+struct PendingUploadInfo {
+    #[serde(flatten)]
+    upload: database::PendingUpload,
+    cross_level_matches: Vec<database::CrossLevelMatch>,
+}
+
 #[derive(Serialize)]
 struct PendingUploadsResponse {
     uploads: Vec<database::PendingUpload>,
@@ -244,7 +533,7 @@ async fn get_pending_uploads(
             Ok(user) => user,
             Err(response) => return response,
         },
-        _ => match authenticate_moderator(&headers, db).await {
+        _ => match admin::moderator_middleware(&headers, db).await {
             Ok(user) => user,
             Err(response) => return response,
         },
@@ -283,7 +572,26 @@ async fn get_pending_uploads(
     match db.get_pending_uploads_paginated(options).await {
         Ok(mut page) => {
             for upload in &mut page.uploads {
-                upload.replacement = is_image_uploaded(upload.level_id as u64).await;
+                upload.replacement = db.index.contains(upload.level_id);
+            }
+
+            // Flag images that are reused across levels, only moderators get to see this
+            if matches!(user.role, database::Role::Moderator | database::Role::Admin) {
+                let ids: Vec<i64> = page.uploads.iter().map(|upload| upload.id).collect();
+                match db.get_cross_level_matches(&ids, DUPLICATE_DISTANCE).await {
+                    Ok(matches) => {
+                        for upload in &mut page.uploads {
+                            upload.cross_level_match =
+                                matches.iter().any(|m| m.pending_id == upload.id);
+                        }
+                    }
+                    Err(e) => {
+                        return util::str_response(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            &format!("Error fetching pending uploads: {}", e),
+                        );
+                    }
+                }
             }
 
             let response = PendingUploadsResponse {
@@ -337,22 +645,43 @@ pub async fn get_pending_info(
     State(db): State<database::AppState>,
     Path(id): Path<i64>,
 ) -> Response {
-    let _user = match authenticate_moderator(&headers, &db).await {
+    let _user = match admin::moderator_middleware(&headers, &db).await {
         Ok(user) => user,
         Err(response) => return response,
     };
 
-    match db.get_pending_upload(id).await {
-        Ok(upload) => Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "application/json")
-            .body(serde_json::to_string(&upload).unwrap().into())
-            .unwrap(),
-        Err(e) => util::str_response(
-            StatusCode::NOT_FOUND,
-            &format!("No pending upload found with ID {}: {}", id, e),
-        ),
-    }
+    let mut upload = match db.get_pending_upload(id).await {
+        Ok(upload) => upload,
+        Err(e) => {
+            return util::str_response(
+                StatusCode::NOT_FOUND,
+                &format!("No pending upload found with ID {}: {}", id, e),
+            );
+        }
+    };
+
+    let cross_level_matches = match db.get_cross_level_matches(&[id], DUPLICATE_DISTANCE).await {
+        Ok(matches) => matches,
+        Err(e) => {
+            return util::str_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("Error fetching matching uploads: {}", e),
+            );
+        }
+    };
+
+    upload.replacement = db.index.contains(upload.level_id);
+    upload.cross_level_match = !cross_level_matches.is_empty();
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(
+            serde_json::to_string(&PendingUploadInfo { upload, cross_level_matches })
+                .unwrap()
+                .into(),
+        )
+        .unwrap()
 }
 
 #[derive(Deserialize, Serialize)]
@@ -367,7 +696,7 @@ pub async fn pending_action(
     Path(id): Path<i64>,
     Json(action): Json<PendingUploadAction>,
 ) -> Response {
-    let user = match authenticate_moderator(&headers, &db).await {
+    let user = match admin::moderator_middleware(&headers, &db).await {
         Ok(user) => user,
         Err(response) => return response,
     };
@@ -392,6 +721,10 @@ pub async fn pending_action(
         // Accept: move image from uploads to thumbnails
         let new_image_path = format!("thumbnails/{}.webp", upload.level_id);
 
+        if let Err(e) = archive_outgoing(&db, upload.level_id).await {
+            return util::str_response(StatusCode::INTERNAL_SERVER_ERROR, &e);
+        }
+
         if let Err(e) = tokio::fs::rename(&old_image_path, &new_image_path).await {
             return util::str_response(
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -406,7 +739,7 @@ pub async fn pending_action(
             );
         }
 
-        cache_controller::purge(upload.level_id);
+        on_thumbnail_accepted(&db, upload.level_id, upload.id).await;
         util::str_response(StatusCode::OK, &format!("Upload {} accepted", id))
     } else {
         // Reject: delete the pending image
@@ -436,7 +769,7 @@ pub async fn get_pending_image(
     State(db): State<database::AppState>,
     Path(id): Path<i64>,
 ) -> Response {
-    let _user = match authenticate_moderator(&headers, &db).await {
+    let _user = match admin::moderator_middleware(&headers, &db).await {
         Ok(user) => user,
         Err(response) => return response,
     };
@@ -0,0 +1,163 @@
+use image::{DynamicImage, GenericImageView, GrayImage};
+
+// Entropy is measured on a small copy of the image, split into columns or rows
+const SAMPLE_WIDTH: u32 = 160;
+const SAMPLE_HEIGHT: u32 = 90;
+const HISTOGRAM_BINS: usize = 16;
+
+// Shannon entropy of the luma values, higher means more detail
+pub fn entropy(values: impl Iterator<Item = u8>) -> f32 {
+    let mut histogram = [0u32; HISTOGRAM_BINS];
+    let mut total = 0;
+    for value in values {
+        histogram[value as usize * HISTOGRAM_BINS / 256] += 1;
+        total += 1;
+    }
+
+    histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f32 / total as f32;
+            p * (1.0 / p).log2() // not -p * log2(p), which is -0 for a single color
+        })
+        .sum()
+}
+
+// Centre (0-1) of the window of the given length with the highest total score,
+// or the middle when every part scores the same
+fn best_window(scores: &[f32], window: usize) -> f32 {
+    let window = window.clamp(1, scores.len());
+    if scores.iter().all(|&score| score == scores[0]) {
+        return 0.5;
+    }
+    let mut sum: f32 = scores[..window].iter().sum();
+    let (mut best_sum, mut best_start) = (sum, 0);
+
+    for start in 1..=scores.len() - window {
+        sum += scores[start + window - 1] - scores[start - 1];
+        if sum > best_sum {
+            (best_sum, best_start) = (sum, start);
+        }
+    }
+
+    (best_start as f32 + window as f32 / 2.0) / scores.len() as f32
+}
+
+// Picks the part of the image to keep when cropping it to the given aspect ratio
+fn entropy_focus(sample: &GrayImage, crop_width: f32, crop_height: f32) -> (f32, f32) {
+    let (width, height) = sample.dimensions();
+
+    if crop_width < 1.0 {
+        let columns: Vec<f32> =
+            (0..width).map(|x| entropy((0..height).map(|y| sample.get_pixel(x, y).0[0]))).collect();
+        (best_window(&columns, (crop_width * width as f32).round() as usize), 0.5)
+    } else if crop_height < 1.0 {
+        let rows: Vec<f32> =
+            (0..height).map(|y| entropy((0..width).map(|x| sample.get_pixel(x, y).0[0]))).collect();
+        (0.5, best_window(&rows, (crop_height * height as f32).round() as usize))
+    } else {
+        (0.5, 0.5)
+    }
+}
+
+// Scales and crops the image to fill the whole box, keeping the focal point (0-1 on each
+// axis) as close to the centre as possible. Without one, the most detailed area is kept.
+pub fn fill(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    focal_point: Option<(f32, f32)>,
+) -> DynamicImage {
+    let (source_width, source_height) = image.dimensions();
+    let scale = f32::max(width as f32 / source_width as f32, height as f32 / source_height as f32);
+    let crop_width = ((width as f32 / scale).round() as u32).clamp(1, source_width);
+    let crop_height = ((height as f32 / scale).round() as u32).clamp(1, source_height);
+
+    let (focus_x, focus_y) = focal_point.unwrap_or_else(|| {
+        let sample = image::imageops::thumbnail(&image.to_luma8(), SAMPLE_WIDTH, SAMPLE_HEIGHT);
+        entropy_focus(
+            &sample,
+            crop_width as f32 / source_width as f32,
+            crop_height as f32 / source_height as f32,
+        )
+    });
+
+    let x = (focus_x * source_width as f32 - crop_width as f32 / 2.0)
+        .clamp(0.0, (source_width - crop_width) as f32) as u32;
+    let y = (focus_y * source_height as f32 - crop_height as f32 / 2.0)
+        .clamp(0.0, (source_height - crop_height) as f32) as u32;
+
+    image.crop_imm(x, y, crop_width, crop_height).resize_exact(
+        width,
+        height,
+        image::imageops::FilterType::Lanczos3,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    // Left half red, right half blue
+    fn halves(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, _| {
+            if x < width / 2 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) }
+        }))
+    }
+
+    #[test]
+    fn best_window_finds_highest_scores() {
+        let scores = [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0];
+        assert_eq!(best_window(&scores, 2), 0.7);
+        assert_eq!(best_window(&scores, 0), 0.65);
+    }
+
+    #[test]
+    fn best_window_falls_back_to_centre() {
+        assert_eq!(best_window(&[2.0; 10], 4), 0.5);
+        assert_eq!(best_window(&[0.0, 1.0, 0.0], 5), 0.5);
+    }
+
+    #[test]
+    fn entropy_of_flat_and_noisy_values() {
+        assert_eq!(entropy([128u8; 64].into_iter()), 0.0);
+        assert_eq!(entropy(0..=255u8), 4.0);
+    }
+
+    #[test]
+    fn fill_keeps_detailed_area() {
+        // noise on the right third, flat everywhere else
+        let image = DynamicImage::ImageLuma8(GrayImage::from_fn(300, 100, |x, y| {
+            if x >= 200 {
+                image::Luma([((x * 37 + y * 91) % 256) as u8])
+            } else {
+                image::Luma([0])
+            }
+        }));
+
+        let cropped = fill(&image, 100, 100, None).to_luma8();
+        assert_eq!(cropped.dimensions(), (100, 100));
+        assert!(cropped.pixels().filter(|pixel| pixel.0[0] == 0).count() < 1000);
+    }
+
+    #[test]
+    fn fill_clamps_focal_point_to_edges() {
+        let image = halves(200, 100);
+
+        let left = fill(&image, 50, 50, Some((0.0, 0.5))).to_rgb8();
+        assert_eq!(left.dimensions(), (50, 50));
+        assert!(left.pixels().all(|pixel| pixel.0[0] > 200 && pixel.0[2] < 50));
+
+        let right = fill(&image, 50, 50, Some((1.0, 1.0))).to_rgb8();
+        assert_eq!(right.dimensions(), (50, 50));
+        assert!(right.pixels().all(|pixel| pixel.0[2] > 200 && pixel.0[0] < 50));
+    }
+
+    #[test]
+    fn fill_upscales_small_images() {
+        let filled = fill(&halves(16, 9), 160, 160, Some((0.5, 0.5)));
+        assert_eq!(filled.dimensions(), (160, 160));
+    }
+}
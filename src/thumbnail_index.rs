@@ -0,0 +1,124 @@
+use crate::history;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::warn;
+
+// Which levels have a thumbnail and how big its file is, so requests never have to scan
+// the thumbnails directory. Built once at startup and refreshed whenever a level changes.
+#[derive(Debug, Default)]
+pub struct ThumbnailIndex {
+    inner: RwLock<IndexData>,
+}
+
+#[derive(Debug, Default)]
+struct IndexData {
+    ids: Vec<i64>, // kept alongside the map to pick random levels in constant time
+    entries: HashMap<i64, IndexEntry>,
+    total_size: u64,
+    generation: u64, // changes whenever a level is added or removed
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    position: usize,
+    size: u64,
+}
+
+impl IndexData {
+    fn insert(&mut self, level_id: i64, size: u64) {
+        if let Some(entry) = self.entries.get_mut(&level_id) {
+            self.total_size = self.total_size - entry.size + size;
+            entry.size = size;
+            return;
+        }
+
+        self.entries.insert(level_id, IndexEntry { position: self.ids.len(), size });
+        self.ids.push(level_id);
+        self.total_size += size;
+        self.generation += 1;
+    }
+
+    fn remove(&mut self, level_id: i64) {
+        let Some(entry) = self.entries.remove(&level_id) else {
+            return;
+        };
+
+        self.ids.swap_remove(entry.position);
+        if let Some(moved) = self.ids.get(entry.position) {
+            self.entries.get_mut(moved).unwrap().position = entry.position;
+        }
+        self.total_size -= entry.size;
+        self.generation += 1;
+    }
+}
+
+impl ThumbnailIndex {
+    pub async fn build(path: &str) -> Result<Self, std::io::Error> {
+        let mut data = IndexData::default();
+        let mut entries = tokio::fs::read_dir(path).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            // skips anything that isn't a thumbnail, like files that are still being written
+            if let Some(name) = entry.file_name().to_str()
+                && let Some(Ok(level_id)) = name.strip_suffix(".webp").map(str::parse::<i64>)
+            {
+                data.insert(level_id, entry.metadata().await?.len());
+            }
+        }
+
+        Ok(Self { inner: RwLock::new(data) })
+    }
+
+    // Re-reads the current thumbnail of a level after it was accepted, replaced or deleted
+    pub async fn refresh(&self, level_id: i64) {
+        match tokio::fs::metadata(history::current_path(level_id)).await {
+            Ok(metadata) => self.inner.write().unwrap().insert(level_id, metadata.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.inner.write().unwrap().remove(level_id)
+            }
+            Err(e) => warn!("Failed to index thumbnail of level {}: {}", level_id, e),
+        }
+    }
+
+    pub fn contains(&self, level_id: i64) -> bool {
+        self.inner.read().unwrap().entries.contains_key(&level_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().ids.len()
+    }
+
+    pub fn size(&self, level_id: i64) -> Option<u64> {
+        self.inner.read().unwrap().entries.get(&level_id).map(|entry| entry.size)
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.inner.read().unwrap().total_size
+    }
+
+    // Every indexed level, sorted so seeded picks don't depend on insertion order
+    pub fn sorted_ids(&self) -> Vec<i64> {
+        let mut ids = self.inner.read().unwrap().ids.clone();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.inner.read().unwrap().generation
+    }
+
+    // Every indexed level, along with the generation they were read at
+    pub fn ids_with_generation(&self) -> (Vec<i64>, u64) {
+        let data = self.inner.read().unwrap();
+        (data.ids.clone(), data.generation)
+    }
+
+    pub fn random(&self) -> Option<i64> {
+        let data = self.inner.read().unwrap();
+        if data.ids.is_empty() {
+            return None;
+        }
+
+        Some(data.ids[rand::random::<u64>() as usize % data.ids.len()])
+    }
+}
@@ -2,7 +2,11 @@ use crate::auth::UserSession;
 use crate::database;
 use axum::http::{HeaderMap, StatusCode, header};
 use axum::response::Response;
+use chrono::NaiveDateTime;
 use serde_json::json;
+use sha2::{Digest, Sha256};
+
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
 
 pub fn response(status: StatusCode, body: serde_json::Value) -> Response {
     Response::builder()
@@ -23,6 +27,87 @@ pub fn str_response(status: StatusCode, message: &str) -> Response {
     )
 }
 
+// Like str_response, with a machine readable error code for clients to match on
+pub fn error_response(status: StatusCode, code: &str, message: &str) -> Response {
+    error_response_with(status, code, message, json!({}))
+}
+
+// Like error_response, with extra fields that give details about the error
+pub fn error_response_with(
+    status: StatusCode,
+    code: &str,
+    message: &str,
+    details: serde_json::Value,
+) -> Response {
+    let mut body = json!({
+        "status": status.as_u16(),
+        "code": code,
+        "message": message,
+    });
+    if let (Some(body), serde_json::Value::Object(details)) = (body.as_object_mut(), details) {
+        body.extend(details);
+    }
+    response(status, body)
+}
+
+// Database timestamps are stored in UTC
+pub fn http_date(time: NaiveDateTime) -> String {
+    time.format(HTTP_DATE_FORMAT).to_string()
+}
+
+// Strong ETag derived from the first 128 bits of the SHA-256 of the body
+pub fn content_etag(data: &[u8]) -> String {
+    let digest = Sha256::digest(data);
+    let hex: String = digest[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("\"{}\"", hex)
+}
+
+// If-None-Match takes precedence over If-Modified-Since, as per RFC 9110
+pub fn is_not_modified(
+    headers: &HeaderMap,
+    etag: &str,
+    last_modified: Option<NaiveDateTime>,
+) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| NaiveDateTime::parse_from_str(h, HTTP_DATE_FORMAT).ok());
+
+    match (since, last_modified) {
+        (Some(since), Some(modified)) => {
+            modified.and_utc().timestamp() <= since.and_utc().timestamp()
+        }
+        _ => false,
+    }
+}
+
+pub fn not_modified(
+    etag: &str,
+    last_modified: Option<NaiveDateTime>,
+    cache_control: &str,
+) -> Response {
+    let mut builder = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, cache_control);
+
+    if let Some(last_modified) = last_modified {
+        builder = builder.header(header::LAST_MODIFIED, http_date(last_modified));
+    }
+
+    builder.body("".into()).unwrap()
+}
+
 fn try_read_cookie(headers: &HeaderMap, cookie_name: &str) -> Option<String> {
     headers.get("Cookie").and_then(|cookie| {
         cookie.to_str().ok().and_then(|cookie_str| {